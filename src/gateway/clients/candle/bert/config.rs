@@ -0,0 +1,59 @@
+// src/gateway/clients/candle/bert/config.rs
+
+/// Candle API Bert Config
+/// Defines the BertModelConfig for sentence-transformers embedding checkpoints.
+
+// Core Crates
+use serde::{Deserialize, Serialize};
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::model_source::{ModelRepo, ModelSource};
+use crate::gateway::clients::candle::pooling::Pooling;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BertModelConfig {
+    pub cpu: bool,
+    pub model_id: Option<String>,
+    pub revision: Option<String>,
+    #[serde(default)]
+    pub model_source: ModelSource,
+    // Most inputs embedded in a single forward pass
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+    #[serde(default)]
+    pub pooling: Pooling,
+    #[serde(default = "default_normalize")]
+    pub normalize: bool,
+}
+
+fn default_max_batch_size() -> usize {
+    32
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
+impl Default for BertModelConfig {
+    fn default() -> Self {
+        Self {
+            cpu: true, // default to the CPU, small embedding models run well without a GPU
+            model_id: None, // model_id can be set later as needed
+            revision: None, // revision can be set later as needed
+            model_source: ModelSource::Hub, // default to downloading from the hub
+            max_batch_size: default_max_batch_size(), // default inputs per forward pass
+            pooling: Pooling::Mean, // default to the sentence-transformers mean pooling
+            normalize: default_normalize(), // default to unit-length embeddings for cosine similarity
+        }
+    }
+}
+
+impl BertModelConfig {
+    // Resolve where the model files are read from
+    pub fn model_repo(&self) -> Result<ModelRepo, CandleError> {
+        let model_id = self.model_id.clone().unwrap_or_else(|| "sentence-transformers/all-MiniLM-L6-v2".to_string());
+
+        ModelRepo::new(&self.model_source, &model_id, self.revision.as_deref())
+    }
+}
\ No newline at end of file
@@ -0,0 +1,9 @@
+// src/gateway/clients/candle/bert/mod.rs
+
+/// Candle API Bert Mods
+/// The main module file for the bert submodule, which orchestrates the initialization and
+/// interaction between the config, model, and tokenizer for sentence embeddings.
+
+pub mod config;
+pub mod model;
+pub mod tokenizer;
\ No newline at end of file
@@ -0,0 +1,160 @@
+// src/gateway/clients/candle/bert/model.rs
+
+/// Candle API Bert Model
+/// Contains code related to downloading weights, initializing the BertModel and serving
+/// batched sentence embeddings.
+
+// Core Crates
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Candle Crates
+use candle_core::{Device, Tensor};
+use candle_nn::var_builder::VarBuilder;
+
+use candle_transformers::models::bert;
+use bert::{Config, DTYPE};
+
+// Networking Crates
+use crate::gateway::clients::candle::bert::config::BertModelConfig;
+use crate::gateway::clients::candle::bert::tokenizer::BertTokenizer;
+use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::llama::generator::LlamaUsage;
+use crate::gateway::clients::candle::pooling::{normalize_l2, pool, Pooling};
+use crate::gateway::clients::candle::select_device;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BertEmbeddingRequest {
+    pub inputs: Vec<String>,
+    // Overrides for the model's default pooling and normalization
+    pub pooling: Option<Pooling>,
+    pub normalize: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BertEmbeddingResponse {
+    // One embedding per input, in request order
+    pub embeddings: Vec<Vec<f32>>,
+    pub usage: LlamaUsage,
+}
+
+pub struct BertModel {
+    pub model: Option<bert::BertModel>,
+    pub bert_config: Option<Config>,
+    pub device: Option<Device>,
+    pub config: BertModelConfig,
+    pub tokenizer: BertTokenizer,
+}
+
+impl BertModel {
+    pub fn new(config: BertModelConfig, tokenizer: BertTokenizer) -> Self {
+        BertModel {
+            model: None,
+            bert_config: None,
+            device: None,
+            config,
+            tokenizer,
+        }
+    }
+
+    // Download the model weights, falling back to the PyTorch checkpoint of older repositories
+    pub async fn download_weights(&self) -> Result<PathBuf, CandleError> {
+        let repo = self.config.model_repo()?;
+
+        match repo.get("model.safetensors").await {
+            Ok(weights_filename) => Ok(weights_filename),
+            Err(_) => repo.get("pytorch_model.bin").await,
+        }
+    }
+
+    pub async fn download_config(&self) -> Result<PathBuf, CandleError> {
+        let repo = self.config.model_repo()?;
+
+        repo.get("config.json").await
+    }
+
+    // Parse the repository's config.json into the Candle Bert config
+    pub fn load_config(&self, config_path: &Path) -> Result<Config, CandleError> {
+        let config_file = fs::read(config_path)
+            .map_err(|_| CandleError::LoadModelError("Failed to read config.json".into()))?;
+        let bert_config: Config = serde_json::from_slice(&config_file)
+            .map_err(|_| CandleError::LoadModelError("Failed to parse config.json".into()))?;
+
+        Ok(bert_config)
+    }
+
+    pub async fn initialize_model(&mut self, weights_path: &Path) -> Result<(), CandleError> {
+
+        println!("Building Bert tokenizer...");
+        self.tokenizer.download_and_load_tokenizer().await?;
+
+        let device = select_device(self.config.cpu)?;
+        println!("Running Bert model on {:?}", device);
+
+        println!("Loading Bert config...");
+        let config_path = self.download_config().await?;
+        let config = self.load_config(&config_path)?;
+        self.tokenizer.set_max_length(config.max_position_embeddings)?;
+
+        let vb = if weights_path.extension().and_then(|extension| extension.to_str()) == Some("bin") {
+            VarBuilder::from_pth(weights_path, DTYPE, &device)?
+        } else {
+            unsafe { VarBuilder::from_mmaped_safetensors(&[weights_path], DTYPE, &device)? }
+        };
+
+        println!("Building Bert model...");
+        let model = bert::BertModel::load(vb, &config)?;
+        self.model = Some(model);
+        self.bert_config = Some(config);
+        self.device = Some(device);
+
+        Ok(())
+    }
+
+    // Embed the inputs in batches of at most max_batch_size, each padded to its longest input
+    pub async fn embed(&self, request: BertEmbeddingRequest) -> Result<BertEmbeddingResponse, CandleError> {
+        // Ensure model is initialized
+        let model = self.model.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let device = self.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+
+        if request.inputs.is_empty() {
+            return Err(CandleError::InvalidParameterError("inputs must not be empty".into()));
+        }
+        if self.config.max_batch_size == 0 {
+            return Err(CandleError::InvalidParameterError("max_batch_size must be > 0".into()));
+        }
+
+        let pooling = request.pooling.unwrap_or(self.config.pooling);
+        let normalize = request.normalize.unwrap_or(self.config.normalize);
+
+        let mut embeddings = Vec::with_capacity(request.inputs.len());
+        let mut prompt_tokens = 0;
+        for inputs in request.inputs.chunks(self.config.max_batch_size) {
+            let encodings = self.tokenizer.encode_batch(inputs)?;
+
+            let mut input_ids = Vec::with_capacity(encodings.len());
+            let mut attention_mask = Vec::with_capacity(encodings.len());
+            for encoding in &encodings {
+                input_ids.push(Tensor::new(encoding.get_ids(), device)?);
+                attention_mask.push(Tensor::new(encoding.get_attention_mask(), device)?);
+                prompt_tokens += encoding.get_attention_mask().iter().filter(|&&mask| mask > 0).count();
+            }
+            let input_ids = Tensor::stack(&input_ids, 0)?;
+            let attention_mask = Tensor::stack(&attention_mask, 0)?;
+            let token_type_ids = input_ids.zeros_like()?;
+
+            let hidden = model.forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+            let mut pooled = pool(&hidden, &attention_mask, pooling)?;
+            if normalize {
+                pooled = normalize_l2(&pooled)?;
+            }
+            embeddings.extend(pooled.to_vec2::<f32>()?);
+        }
+
+        Ok(BertEmbeddingResponse {
+            embeddings,
+            usage: LlamaUsage::new(prompt_tokens, 0),
+        })
+    }
+}
\ No newline at end of file
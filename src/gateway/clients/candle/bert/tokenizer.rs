@@ -0,0 +1,62 @@
+// src/gateway/clients/candle/bert/tokenizer.rs
+
+/// Candle API Bert Tokenizer
+/// Handles downloading and initializing the tokenizer, with padding and truncation for batches.
+
+// Candle Crates
+use tokenizers::{Encoding, PaddingParams, TruncationParams};
+use tokenizers::{tokenizer::Tokenizer as HfTokenizer};
+
+// Networking Crates
+use crate::gateway::clients::candle::bert::config::BertModelConfig;
+use crate::gateway::clients::candle::candle_error::CandleError;
+
+pub struct BertTokenizer {
+    pub tokenizer: Option<HfTokenizer>,
+    pub model_config: BertModelConfig,
+}
+
+impl BertTokenizer {
+    pub fn new(model_config: BertModelConfig) -> Self {
+        BertTokenizer {
+            tokenizer: None,
+            model_config,
+        }
+    }
+
+    pub async fn download_and_load_tokenizer(&mut self) -> Result<(), CandleError> {
+        let repo = self.model_config.model_repo()?;
+
+        let tokenizer_filename = repo.get("tokenizer.json").await?;
+
+        let mut tokenizer = HfTokenizer::from_file(&tokenizer_filename).map_err(|_| CandleError::LoadModelError("Failed to load tokenizer".into()))?;
+        println!("Tokenizer loaded for model {}", self.model_config.model_id.as_ref().unwrap_or(&"default-model".to_string()));
+
+        // Pad every batch to its longest input, the attention mask marks the padding
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        self.tokenizer = Some(tokenizer);
+
+        Ok(())
+    }
+
+    // Truncate inputs to the model's maximum length, special tokens included
+    pub fn set_max_length(&mut self, max_length: usize) -> Result<(), CandleError> {
+        let tokenizer = self.tokenizer.as_mut().ok_or_else(|| CandleError::UninitializedModelError("Tokenizer is not initialized".into()))?;
+
+        tokenizer
+            .with_truncation(Some(TruncationParams { max_length, ..Default::default() }))
+            .map_err(|_| CandleError::InitializationError("Failed to configure tokenizer truncation".into()))?;
+
+        Ok(())
+    }
+
+    pub fn encode_batch(&self, inputs: &[String]) -> Result<Vec<Encoding>, CandleError> {
+        let tokenizer = self.tokenizer.as_ref().ok_or_else(|| CandleError::UninitializedModelError("Tokenizer is not initialized".into()))?;
+
+        let encodings = tokenizer
+            .encode_batch(inputs.to_vec(), true)
+            .map_err(|_| CandleError::EncodingError("Failed to encode text".into()))?;
+
+        Ok(encodings)
+    }
+}
\ No newline at end of file
@@ -41,12 +41,21 @@ pub enum CandleError {
     #[error("Initialization error: {0}")]
     InitializationError(CoreError),
 
+    #[error("Invalid parameter: {0}")]
+    InvalidParameterError(String),
+
     #[error("Error loading model: {0}")]
     LoadModelError(CoreError),
 
     #[error("SafeTensor model: {0}")]
     SafeTensorError(CoreError),
 
+    #[error("Scheduler error: {0}")]
+    SchedulerError(String),
+
+    #[error("Chat template error: {0}")]
+    TemplateError(String),
+
     #[error("Tokenization error: {0}")]
     TokenError(TokenError),
 
@@ -111,8 +120,11 @@ impl From<CandleError> for ClientError {
             CandleError::GenericError(err) => ClientError::GenericError(format!("Generic client error: {}", err)),
             CandleError::EncodingError(err) => ClientError::SpecificError(format!("Encoding error: {}", err)),
             CandleError::InitializationError(err) => ClientError::SpecificError(format!("Initialization error: {}", err)),
+            CandleError::InvalidParameterError(err) => ClientError::SpecificError(format!("Invalid parameter: {}", err)),
             CandleError::LoadModelError(err) => ClientError::SpecificError(format!("Error loading model: {}", err)),
             CandleError::SafeTensorError(err) => ClientError::SpecificError(format!("SafeTensor error: {}", err)),
+            CandleError::SchedulerError(err) => ClientError::SpecificError(format!("Scheduler error: {}", err)),
+            CandleError::TemplateError(err) => ClientError::SpecificError(format!("Chat template error: {}", err)),
             CandleError::TokenError(err) => ClientError::SpecificError(format!("Token error: {}", err)),
             CandleError::UninitializedModelError(err) => ClientError::SpecificError(format!("Uninitialized model error: {}", err)),
             CandleError::UnexpectedDTypeError(err) => ClientError::SpecificError(format!("Unexpected DType: {}", err)),
@@ -0,0 +1,416 @@
+// src/gateway/clients/candle/llama/batch.rs
+
+/// Candle API Llama Batch
+/// A running batch of sequences decoded together, one forward pass per step.
+/// Sequences can join between steps and are retired independently as they finish.
+
+// Core Crates
+use std::time::{Duration, Instant};
+
+// Candle Crates
+use candle_core::Tensor;
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::llama::generator::{LlamaGenerateTextResponse, LlamaSequence, LlamaTiming};
+use crate::gateway::clients::candle::llama::model::LlamaModel;
+use crate::gateway::clients::candle::llama::transformer::{attention_mask, LlamaCache};
+
+// Token fed into padded batch positions, it is always masked out so any id works
+pub const PAD_TOKEN_ID: u32 = 0;
+
+// Token counts the admission decisions are made on
+pub trait LlamaBatchSlot {
+    fn prompt_tokens(&self) -> usize;
+    // Tokens left before sample_len is reached
+    fn remaining_tokens(&self) -> usize;
+    // Upper bound on the tokens held in the cache once finished
+    fn reserved_tokens(&self) -> usize;
+}
+
+impl LlamaBatchSlot for LlamaSequence<'_> {
+    fn prompt_tokens(&self) -> usize {
+        LlamaSequence::prompt_tokens(self)
+    }
+
+    fn remaining_tokens(&self) -> usize {
+        LlamaSequence::remaining_tokens(self)
+    }
+
+    fn reserved_tokens(&self) -> usize {
+        LlamaSequence::reserved_tokens(self)
+    }
+}
+
+// Sequences prefilled together all end on the longest prompt, so each must finish before max_position
+pub fn fits_prefill<'s, S: LlamaBatchSlot + 's>(sequences: impl IntoIterator<Item = &'s S>, max_position: usize) -> bool {
+    let (max_len, max_remaining) = sequences.into_iter()
+        .fold((0, 0), |(max_len, max_remaining), sequence| {
+            (max_len.max(sequence.prompt_tokens()), max_remaining.max(sequence.remaining_tokens()))
+        });
+
+    max_len + max_remaining <= max_position
+}
+
+// A joining sequence ends its prompt on the batch position and decodes from there
+pub fn fits_join(sequence: &impl LlamaBatchSlot, index_pos: usize, max_position: usize) -> bool {
+    sequence.prompt_tokens() <= index_pos && index_pos + sequence.remaining_tokens() <= max_position
+}
+
+// A sequence in the batch, tagged by the caller to route its response
+pub struct LlamaBatchEntry<'a, T> {
+    pub sequence: LlamaSequence<'a>,
+    pub tag: T,
+    generated_text: String,
+    start: Instant,
+    prompt_duration: Option<Duration>,
+}
+
+impl<'a, T> LlamaBatchEntry<'a, T> {
+    fn new(sequence: LlamaSequence<'a>, tag: T, start: Instant) -> Self {
+        LlamaBatchEntry {
+            sequence,
+            tag,
+            generated_text: String::new(),
+            start,
+            prompt_duration: None,
+        }
+    }
+
+    fn sample(&mut self, logits: &Tensor) -> Result<(), CandleError> {
+        if let Some(token) = self.sequence.sample(logits)? {
+            if let Some(text) = self.sequence.decode_next(token)? {
+                self.generated_text.push_str(&text);
+            }
+        }
+        self.prompt_duration.get_or_insert_with(|| self.start.elapsed());
+
+        Ok(())
+    }
+
+    // Flush the remaining text and build the response of a finished sequence
+    pub fn finish(mut self) -> (T, Result<LlamaGenerateTextResponse, CandleError>) {
+        let response = self.response();
+
+        (self.tag, response)
+    }
+
+    fn response(&mut self) -> Result<LlamaGenerateTextResponse, CandleError> {
+        if let Some(text) = self.sequence.decode_rest()? {
+            self.generated_text.push_str(&text);
+        }
+        let timing = LlamaTiming::new(self.prompt_duration, self.start.elapsed());
+
+        self.sequence.response(std::mem::take(&mut self.generated_text), timing)
+    }
+}
+
+// Every row shares the same rope position, shorter prompts are placed so they end on it
+// Cache columns left of a row's prompt are padding and masked out
+pub struct LlamaBatch<'a, T> {
+    model: &'a LlamaModel,
+    entries: Vec<LlamaBatchEntry<'a, T>>,
+    cache: Option<LlamaCache>,
+    padding_mask: Vec<Vec<bool>>,
+    index_pos: usize,
+}
+
+impl<'a, T> LlamaBatch<'a, T> {
+    pub fn new(model: &'a LlamaModel) -> Self {
+        LlamaBatch {
+            model,
+            entries: Vec::new(),
+            cache: None,
+            padding_mask: Vec::new(),
+            index_pos: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Tokens reserved by the running sequences, counting each at its full prompt plus sample_len
+    pub fn reserved_tokens(&self) -> usize {
+        self.entries.iter().map(|entry| entry.sequence.reserved_tokens()).sum()
+    }
+
+    // Positions available to every row, bounded by the rope table
+    pub fn max_position(&self) -> Result<usize, CandleError> {
+        let llama_config = self.model.llama_config.as_ref().ok_or(CandleError::UninitializedModelError)?;
+
+        Ok(llama_config.max_position_embeddings)
+    }
+
+    // A sequence can join a running batch when its prompt fits before the current position
+    // and it can finish before running out of positions
+    pub fn can_join(&self, sequence: &LlamaSequence) -> Result<bool, CandleError> {
+        Ok(!self.is_empty() && fits_join(sequence, self.index_pos, self.max_position()?))
+    }
+
+    // Start an empty batch, left-padding the prompts into a single prefill pass
+    // Returns the sequences that finished on their first token, on failure the sequences are
+    // left in the batch so clear() hands them back with their tags
+    pub fn prefill(&mut self, sequences: Vec<(LlamaSequence<'a>, T)>) -> Result<Vec<LlamaBatchEntry<'a, T>>, CandleError> {
+        if !self.is_empty() {
+            return Err(CandleError::InvalidParameterError("Cannot prefill a running batch".into()));
+        }
+
+        let start = Instant::now();
+        self.entries = sequences.into_iter()
+            .map(|(sequence, tag)| LlamaBatchEntry::new(sequence, tag, start))
+            .collect();
+
+        self.prefill_entries()
+    }
+
+    fn prefill_entries(&mut self) -> Result<Vec<LlamaBatchEntry<'a, T>>, CandleError> {
+        // Ensure model is initialized
+        let model = self.model.model.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let llama_config = self.model.llama_config.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let device = self.model.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let dtype = self.model.dtype.ok_or(CandleError::UninitializedModelError)?;
+
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        if !fits_prefill(self.entries.iter().map(|entry| &entry.sequence), llama_config.max_position_embeddings) {
+            return Err(CandleError::InvalidParameterError("Sequences do not fit the context window when batched together".into()));
+        }
+
+        // Left-pad the prompts so every row ends at the same position
+        let max_len = self.entries.iter().map(|entry| entry.sequence.prompt_tokens()).max().unwrap_or(0);
+        let mut input = Vec::with_capacity(self.entries.len() * max_len);
+        self.padding_mask.clear();
+        for entry in &self.entries {
+            let pad_len = max_len - entry.sequence.prompt_tokens();
+            input.extend(std::iter::repeat(PAD_TOKEN_ID).take(pad_len));
+            input.extend_from_slice(entry.sequence.tokens());
+            self.padding_mask.push((0..max_len).map(|position| position >= pad_len).collect::<Vec<bool>>());
+        }
+        let input = Tensor::from_vec(input, (self.entries.len(), max_len), device)?;
+
+        let mut cache = LlamaCache::new(true, dtype, llama_config, device)?;
+        let mask = attention_mask(&self.padding_mask, max_len, device)?;
+        let logits = model.forward(&input, 0, &mut cache, Some(&mask))?;
+        self.cache = Some(cache);
+        self.index_pos = max_len;
+
+        self.sample_rows(&logits)
+    }
+
+    // Start an empty batch from sequences sharing one prompt
+    // The prompt is processed once and its cache copied into every row
+    pub fn prefill_shared(&mut self, sequences: Vec<(LlamaSequence<'a>, T)>) -> Result<Vec<LlamaBatchEntry<'a, T>>, CandleError> {
+        // Ensure model is initialized
+        let model = self.model.model.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let llama_config = self.model.llama_config.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let device = self.model.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let dtype = self.model.dtype.ok_or(CandleError::UninitializedModelError)?;
+
+        if !self.is_empty() {
+            return Err(CandleError::InvalidParameterError("Cannot prefill a running batch".into()));
+        }
+        let prompt = match sequences.first() {
+            Some((sequence, _)) => sequence.tokens().to_vec(),
+            None => return Ok(Vec::new()),
+        };
+        if sequences.iter().any(|(sequence, _)| sequence.tokens() != prompt.as_slice()) {
+            return Err(CandleError::InvalidParameterError("Shared prefill needs identical prompts".into()));
+        }
+
+        let start = Instant::now();
+        let input = Tensor::new(prompt.as_slice(), device)?.unsqueeze(0)?;
+        let mut cache = LlamaCache::new(true, dtype, llama_config, device)?;
+        let logits = model.forward(&input, 0, &mut cache, None)?;
+
+        let rows = Tensor::new(vec![0u32; sequences.len()], device)?;
+        cache.select_rows(&rows)?;
+        let logits = logits.index_select(&rows, 0)?;
+
+        self.padding_mask = vec![vec![true; prompt.len()]; sequences.len()];
+        self.entries = sequences.into_iter()
+            .map(|(sequence, tag)| LlamaBatchEntry::new(sequence, tag, start))
+            .collect();
+        self.cache = Some(cache);
+        self.index_pos = prompt.len();
+
+        self.sample_rows(&logits)
+    }
+
+    // Add a sequence to the running batch between decode steps
+    // Returns the entry when it finished on its first token, on failure the tag is handed back with
+    // the error and the running batch is left untouched
+    pub fn join(&mut self, sequence: LlamaSequence<'a>, tag: T) -> Result<Option<LlamaBatchEntry<'a, T>>, (T, CandleError)> {
+        let mut entry = LlamaBatchEntry::new(sequence, tag, Instant::now());
+
+        match self.join_entry(&mut entry) {
+            Ok(true) => {
+                self.entries.push(entry);
+                Ok(None)
+            }
+            Ok(false) => Ok(Some(entry)),
+            Err(err) => Err((entry.tag, err)),
+        }
+    }
+
+    // The prompt is prefilled on its own so that it ends on the batch position, then its cache is
+    // padded on the left to line up with the other rows
+    // Returns whether the entry is still running and now has a row in the batch
+    fn join_entry(&mut self, entry: &mut LlamaBatchEntry<'a, T>) -> Result<bool, CandleError> {
+        // Ensure model is initialized
+        let model = self.model.model.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let llama_config = self.model.llama_config.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let device = self.model.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let dtype = self.model.dtype.ok_or(CandleError::UninitializedModelError)?;
+
+        if !self.can_join(&entry.sequence)? {
+            return Err(CandleError::InvalidParameterError("Sequence cannot join the running batch".into()));
+        }
+
+        let prompt_len = entry.sequence.prompt_tokens();
+        let input = Tensor::new(entry.sequence.tokens(), device)?.unsqueeze(0)?;
+        let mut cache = LlamaCache::new(true, dtype, llama_config, device)?;
+        let logits = model.forward(&input, self.index_pos - prompt_len, &mut cache, None)?;
+
+        entry.sample(&logits.get(0)?)?;
+        if entry.sequence.is_finished() {
+            return Ok(false);
+        }
+
+        let key_len = self.padding_mask.first().map(|keys| keys.len()).unwrap_or(prompt_len);
+        cache.pad_left(key_len - prompt_len)?;
+        self.cache
+            .as_mut()
+            .ok_or(CandleError::UninitializedModelError)?
+            .append_rows(&cache)?;
+        self.padding_mask.push((0..key_len).map(|position| position >= key_len - prompt_len).collect());
+
+        Ok(true)
+    }
+
+    // Decode one token for every running sequence, returning the ones that finished
+    pub fn step(&mut self) -> Result<Vec<LlamaBatchEntry<'a, T>>, CandleError> {
+        // Ensure model is initialized
+        let model = self.model.model.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let device = self.model.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Every running sequence feeds back the token it sampled last
+        let next_tokens = self.entries.iter()
+            .filter_map(|entry| entry.sequence.tokens().last().copied())
+            .collect::<Vec<u32>>();
+        let input = Tensor::new(next_tokens, device)?.unsqueeze(1)?;
+        for keys in self.padding_mask.iter_mut() {
+            keys.push(true);
+        }
+
+        let cache = self.cache.as_mut().ok_or(CandleError::UninitializedModelError)?;
+        let mask = attention_mask(&self.padding_mask, 1, device)?;
+        let logits = model.forward(&input, self.index_pos, cache, Some(&mask))?;
+        self.index_pos += 1;
+
+        self.sample_rows(&logits)
+    }
+
+    // Take every sequence out of the batch, used to fail them all when a step errors
+    pub fn clear(&mut self) -> Vec<LlamaBatchEntry<'a, T>> {
+        self.cache = None;
+        self.padding_mask.clear();
+        self.index_pos = 0;
+
+        std::mem::take(&mut self.entries)
+    }
+
+    // Sample every row, then retire the finished rows so they no longer cost compute
+    fn sample_rows(&mut self, logits: &Tensor) -> Result<Vec<LlamaBatchEntry<'a, T>>, CandleError> {
+        let device = self.model.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+
+        let mut kept_rows = Vec::with_capacity(self.entries.len());
+        for (row, entry) in self.entries.iter_mut().enumerate() {
+            entry.sample(&logits.get(row)?)?;
+            if !entry.sequence.is_finished() {
+                kept_rows.push(row);
+            }
+        }
+
+        if kept_rows.is_empty() {
+            return Ok(self.clear());
+        }
+        if kept_rows.len() == self.entries.len() {
+            return Ok(Vec::new());
+        }
+
+        let rows = Tensor::new(kept_rows.iter().map(|&row| row as u32).collect::<Vec<u32>>(), device)?;
+        if let Some(cache) = self.cache.as_mut() {
+            cache.select_rows(&rows)?;
+        }
+        self.padding_mask = kept_rows.iter().map(|&row| self.padding_mask[row].clone()).collect();
+
+        let mut finished = Vec::with_capacity(self.entries.len() - kept_rows.len());
+        let mut kept = Vec::with_capacity(kept_rows.len());
+        for (row, entry) in std::mem::take(&mut self.entries).into_iter().enumerate() {
+            if kept_rows.contains(&row) {
+                kept.push(entry);
+            } else {
+                finished.push(entry);
+            }
+        }
+        self.entries = kept;
+
+        Ok(finished)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Slot with fixed token counts, shared with the scheduler tests
+    pub struct MockSlot {
+        prompt_tokens: usize,
+        remaining_tokens: usize,
+    }
+
+    impl LlamaBatchSlot for MockSlot {
+        fn prompt_tokens(&self) -> usize {
+            self.prompt_tokens
+        }
+
+        fn remaining_tokens(&self) -> usize {
+            self.remaining_tokens
+        }
+
+        fn reserved_tokens(&self) -> usize {
+            self.prompt_tokens + self.remaining_tokens
+        }
+    }
+
+    pub fn slot(prompt_tokens: usize, remaining_tokens: usize) -> MockSlot {
+        MockSlot { prompt_tokens, remaining_tokens }
+    }
+
+    #[test]
+    fn test_prefill_ends_every_row_on_the_longest_prompt() {
+        let long = slot(90, 10);
+        let short = slot(10, 90);
+        assert!(fits_prefill([&long], 100));
+        assert!(fits_prefill([&short], 100));
+        assert!(!fits_prefill([&long, &short], 100));
+    }
+
+    #[test]
+    fn test_join_needs_room_before_and_after_the_position() {
+        let sequence = slot(20, 30);
+        assert!(fits_join(&sequence, 20, 50));
+        assert!(!fits_join(&sequence, 19, 50));
+        assert!(!fits_join(&sequence, 21, 50));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,400 @@
+// src/gateway/clients/candle/llama/beam_search.rs
+
+/// Candle API Llama Beam Search
+/// Deterministic decoding that keeps the most likely partial completions at every step,
+/// an alternative to sampling for translation and structured-extraction prompts.
+
+// Core Crates
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+// Candle Crates
+use candle_core::Tensor;
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::logits::{log_softmax, top_n, LogitsPipeline};
+use crate::gateway::clients::candle::llama::context::{fit_context, LlamaContextReport};
+use crate::gateway::clients::candle::llama::generator::{
+    LlamaFinishReason, LlamaGenerateTextRequest, LlamaGenerateTextResponse, LlamaStopSequences, LlamaTiming, LlamaUsage,
+};
+use crate::gateway::clients::candle::llama::model::LlamaModel;
+use crate::gateway::clients::candle::llama::tokenizer::LlamaTokenOutputStream;
+use crate::gateway::clients::candle::llama::transformer::LlamaCache;
+
+// Widest beam accepted, each beam is a row of the batch
+const MAX_BEAM_WIDTH: usize = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlamaBeamSearchParams {
+    pub beam_width: usize,
+    // Scores are negative and divided by length^length_penalty, so any value above 0 favours
+    // longer completions and values below 0 favour shorter ones
+    #[serde(default = "default_length_penalty")]
+    pub length_penalty: f32,
+    // Stop once beam_width completions have finished rather than when no running beam can beat them
+    #[serde(default)]
+    pub early_stopping: bool,
+    // Number of best completions to return, at most beam_width
+    #[serde(default = "default_num_return_sequences")]
+    pub num_return_sequences: usize,
+}
+
+fn default_length_penalty() -> f32 {
+    1.0
+}
+
+fn default_num_return_sequences() -> usize {
+    1
+}
+
+impl LlamaBeamSearchParams {
+    pub fn validate(&self) -> Result<(), CandleError> {
+        if !(1..=MAX_BEAM_WIDTH).contains(&self.beam_width) {
+            return Err(CandleError::InvalidParameterError(format!("beam_width must be in [1, {}], got {}", MAX_BEAM_WIDTH, self.beam_width)));
+        }
+
+        if !self.length_penalty.is_finite() {
+            return Err(CandleError::InvalidParameterError(format!("length_penalty must be finite, got {}", self.length_penalty)));
+        }
+
+        if !(1..=self.beam_width).contains(&self.num_return_sequences) {
+            return Err(CandleError::InvalidParameterError(format!("num_return_sequences must be in [1, beam_width], got {}", self.num_return_sequences)));
+        }
+
+        Ok(())
+    }
+}
+
+// A completion returned by beam search, best first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlamaBeam {
+    pub text: String,
+    // Length-penalized sum of the token log-probabilities
+    pub score: f32,
+    pub completion_tokens: usize,
+    pub finish_reason: LlamaFinishReason,
+}
+
+// A partial or finished completion, with the decoder state D that follows its text
+struct LlamaBeamHypothesis<D> {
+    tokens: Vec<u32>,
+    logprob: f32,
+    finish_reason: LlamaFinishReason,
+    decoder: D,
+}
+
+impl<D> LlamaBeamHypothesis<D> {
+    fn score(&self, length_penalty: f32) -> f32 {
+        self.logprob / (self.tokens.len().max(1) as f32).powf(length_penalty)
+    }
+}
+
+// Order hypotheses best first by their length-penalized score
+fn rank<D>(hypotheses: &mut [LlamaBeamHypothesis<D>], length_penalty: f32) {
+    hypotheses.sort_by(|a, b| b.score(length_penalty).total_cmp(&a.score(length_penalty)));
+}
+
+// Walk the candidates, best first, into the next running beams and their parent rows
+// extend derives the decoder of a candidate from its parent and tells whether the token ends the
+// hypothesis, only candidates ranked within the beam width complete one
+// End-of-sequence tokens are not part of the completion, stop matches are
+fn select_candidates<D>(
+    running: &[LlamaBeamHypothesis<D>],
+    candidates: &[(f32, usize, u32)],
+    beam_width: usize,
+    finished: &mut Vec<LlamaBeamHypothesis<D>>,
+    mut extend: impl FnMut(&LlamaBeamHypothesis<D>, u32) -> Result<(Option<LlamaFinishReason>, D), CandleError>,
+) -> Result<(Vec<LlamaBeamHypothesis<D>>, Vec<u32>), CandleError> {
+    let mut next = Vec::with_capacity(beam_width);
+    let mut parents = Vec::with_capacity(beam_width);
+    for (rank, &(logprob, row, token)) in candidates.iter().enumerate() {
+        let parent = &running[row];
+        let (finish_reason, decoder) = extend(parent, token)?;
+        let mut tokens = parent.tokens.clone();
+
+        if let Some(finish_reason) = finish_reason {
+            if rank < beam_width {
+                if finish_reason != LlamaFinishReason::Eos {
+                    tokens.push(token);
+                }
+                finished.push(LlamaBeamHypothesis { tokens, logprob, finish_reason, decoder });
+            }
+            continue;
+        }
+
+        tokens.push(token);
+        next.push(LlamaBeamHypothesis { tokens, logprob, finish_reason: LlamaFinishReason::Length, decoder });
+        parents.push(row as u32);
+        if next.len() == beam_width {
+            break;
+        }
+    }
+
+    Ok((next, parents))
+}
+
+// Incremental detokenization and stop matching of one beam, cloned when the beam is extended
+// so every token is decoded and matched once
+#[derive(Clone)]
+struct LlamaBeamDecoder<'a> {
+    output: LlamaTokenOutputStream<'a>,
+    stop_sequences: LlamaStopSequences,
+    text: String,
+    stopped: bool,
+}
+
+impl<'a> LlamaBeamDecoder<'a> {
+    // Push a token, returning whether the completion now reaches a stop sequence
+    fn push(&mut self, token: u32) -> Result<bool, CandleError> {
+        if let Some(chunk) = self.output.next_token(token)? {
+            self.push_text(&chunk);
+        }
+
+        Ok(self.stopped)
+    }
+
+    fn push_text(&mut self, chunk: &str) {
+        let (text, stopped) = self.stop_sequences.push(chunk);
+        self.text.push_str(&text);
+        self.stopped = stopped;
+    }
+
+    // Completion text once no more tokens follow, cut at the first stop sequence
+    fn finish(mut self) -> Result<(String, bool), CandleError> {
+        if !self.stopped {
+            if let Some(rest) = self.output.decode_rest()? {
+                self.push_text(&rest);
+            }
+        }
+        if !self.stopped {
+            let rest = self.stop_sequences.flush();
+            self.text.push_str(&rest);
+        }
+
+        Ok((self.text, self.stopped))
+    }
+}
+
+pub struct LlamaBeamSearch<'a> {
+    model: &'a LlamaModel,
+    params: LlamaBeamSearchParams,
+    logits_pipeline: LogitsPipeline,
+    sample_len: usize,
+    prompt: Vec<u32>,
+    stop: Vec<String>,
+    echo: bool,
+    context: Option<LlamaContextReport>,
+}
+
+impl<'a> LlamaBeamSearch<'a> {
+    pub fn new(model: &'a LlamaModel, request: &LlamaGenerateTextRequest) -> Result<Self, CandleError> {
+        model.require_full_precision("Beam search")?;
+
+        // Validate the request before doing any work
+        let params = request.beam_search.clone()
+            .ok_or_else(|| CandleError::InvalidParameterError("beam_search is not set".into()))?;
+        params.validate()?;
+        if request.completions()? > 1 {
+            return Err(CandleError::InvalidParameterError("n > 1 is not supported with beam search, use num_return_sequences".into()));
+        }
+        if request.logprobs || request.top_logprobs.is_some() {
+            return Err(CandleError::InvalidParameterError("logprobs are not supported with beam search".into()));
+        }
+        if request.stop.iter().any(|stop| stop.is_empty()) {
+            return Err(CandleError::InvalidParameterError("stop sequences must not be empty".into()));
+        }
+
+        // Penalties and biases still apply, the temperature and filtering settings do not
+        let mut sampling = request.sampling.resolve(&model.config)?;
+        sampling.greedy = true;
+
+        // Make the prompt and completion fit the context window
+        let tokens = model.tokenizer.encode(&request.prompt, true)?;
+        let truncation = request.truncation.unwrap_or(model.config.truncation);
+        let fit = fit_context(tokens, sampling.sample_len, model.context_length()?, truncation)?;
+
+        Ok(LlamaBeamSearch {
+            model,
+            params,
+            logits_pipeline: sampling.logits_pipeline(fit.tokens.len()),
+            sample_len: fit.sample_len,
+            prompt: fit.tokens,
+            stop: request.stop.clone(),
+            echo: request.echo,
+            context: fit.report,
+        })
+    }
+
+    pub fn run(self) -> Result<LlamaGenerateTextResponse, CandleError> {
+        // Ensure model is initialized
+        let model = self.model.model.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let llama_config = self.model.llama_config.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let device = self.model.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let dtype = self.model.dtype.ok_or(CandleError::UninitializedModelError)?;
+
+        println!("Starting the beam search with {} beams...", self.params.beam_width);
+        let start = Instant::now();
+        let beam_width = self.params.beam_width;
+
+        // The prompt is processed once, the cache rows are then copied to follow the beams
+        let mut cache = LlamaCache::new(true, dtype, llama_config, device)?;
+        let input = Tensor::new(self.prompt.as_slice(), device)?.unsqueeze(0)?;
+        let mut logits = model.forward(&input, 0, &mut cache, None)?;
+        let mut index_pos = self.prompt.len();
+        let prompt_duration = start.elapsed();
+
+        let decoder = LlamaBeamDecoder {
+            output: self.model.tokenizer.output_stream_after(&self.prompt),
+            stop_sequences: LlamaStopSequences::new(self.stop.clone()),
+            text: String::new(),
+            stopped: false,
+        };
+        let mut running = vec![LlamaBeamHypothesis { tokens: Vec::new(), logprob: 0.0, finish_reason: LlamaFinishReason::Length, decoder }];
+        let mut finished = Vec::new();
+
+        for step in 0..self.sample_len {
+            // Expand every running beam with its most likely next tokens
+            let mut candidates = Vec::with_capacity(running.len() * 2 * beam_width);
+            for (row, beam) in running.iter().enumerate() {
+                let context = [self.prompt.as_slice(), &beam.tokens].concat();
+                let logprobs = log_softmax(&self.logits_pipeline.process(&logits.get(row)?, &context)?);
+                for (token, logprob) in top_n(&logprobs, 2 * beam_width) {
+                    candidates.push((beam.logprob + logprob, row, token));
+                }
+            }
+            candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            // A hypothesis ends at end of sequence or once its text reaches a stop sequence,
+            // so nothing past the stop counts towards its score
+            let (next, parents) = select_candidates(&running, &candidates, beam_width, &mut finished, |parent, token| {
+                let mut decoder = parent.decoder.clone();
+                if self.model.tokenizer.is_eos_token(token) {
+                    return Ok((Some(LlamaFinishReason::Eos), decoder));
+                }
+                let stopped = decoder.push(token)?;
+
+                Ok((Some(LlamaFinishReason::StopSequence).filter(|_| stopped), decoder))
+            })?;
+
+            rank(&mut finished, self.params.length_penalty);
+            finished.truncate(beam_width);
+            running = next;
+            if running.is_empty() || self.is_done(&finished, &running) || step + 1 == self.sample_len {
+                break;
+            }
+
+            // Reorder the cache rows so each beam continues from its parent
+            cache.select_rows(&Tensor::new(parents, device)?)?;
+            let next_tokens = running.iter()
+                .filter_map(|beam| beam.tokens.last().copied())
+                .collect::<Vec<u32>>();
+            let input = Tensor::new(next_tokens, device)?.unsqueeze(1)?;
+            logits = model.forward(&input, index_pos, &mut cache, None)?;
+            index_pos += 1;
+        }
+
+        // Beams still running at sample_len compete with the finished ones
+        finished.extend(running);
+        rank(&mut finished, self.params.length_penalty);
+        finished.truncate(self.params.num_return_sequences);
+
+        let mut beams = Vec::with_capacity(finished.len());
+        for hypothesis in finished {
+            beams.push(self.beam(hypothesis)?);
+        }
+        let best = beams.first()
+            .cloned()
+            .ok_or_else(|| CandleError::InvalidParameterError("Beam search produced no completion".into()))?;
+
+        let prompt = if self.echo {
+            Some(self.model.tokenizer.decode(&self.prompt, true)?)
+        } else {
+            None
+        };
+
+        Ok(LlamaGenerateTextResponse {
+            generated_text: best.text,
+            prompt,
+            finish_reason: best.finish_reason,
+            usage: LlamaUsage::new(self.prompt.len(), best.completion_tokens),
+            timing: LlamaTiming::new(Some(prompt_duration), start.elapsed()),
+            logprobs: None,
+            beams: Some(beams),
+            choices: None,
+            context: self.context.clone(),
+        })
+    }
+
+    // Done once the beam is full of finished hypotheses that no running beam can beat
+    fn is_done(&self, finished: &[LlamaBeamHypothesis<LlamaBeamDecoder>], running: &[LlamaBeamHypothesis<LlamaBeamDecoder>]) -> bool {
+        if finished.len() < self.params.beam_width {
+            return false;
+        }
+        if self.params.early_stopping {
+            return true;
+        }
+
+        let worst_finished = finished.iter()
+            .map(|hypothesis| hypothesis.score(self.params.length_penalty))
+            .fold(f32::INFINITY, f32::min);
+        let best_running = running.iter()
+            .map(|hypothesis| hypothesis.score(self.params.length_penalty))
+            .fold(f32::NEG_INFINITY, f32::max);
+
+        worst_finished >= best_running
+    }
+
+    // Finish decoding a hypothesis into a returned beam
+    fn beam(&self, hypothesis: LlamaBeamHypothesis<LlamaBeamDecoder>) -> Result<LlamaBeam, CandleError> {
+        let score = hypothesis.score(self.params.length_penalty);
+        let completion_tokens = hypothesis.tokens.len();
+        let (text, stopped) = hypothesis.decoder.finish()?;
+        let finish_reason = if stopped { LlamaFinishReason::StopSequence } else { hypothesis.finish_reason };
+
+        Ok(LlamaBeam {
+            text,
+            score,
+            completion_tokens,
+            finish_reason,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hypothesis(tokens: Vec<u32>, logprob: f32) -> LlamaBeamHypothesis<()> {
+        LlamaBeamHypothesis { tokens, logprob, finish_reason: LlamaFinishReason::Length, decoder: () }
+    }
+
+    #[test]
+    fn test_length_penalty_ranks_hypotheses() {
+        let mut hypotheses = vec![hypothesis(vec![1, 2], -3.0), hypothesis(vec![1, 2, 3, 4], -4.0)];
+        rank(&mut hypotheses, 1.0);
+        assert_eq!(hypotheses[0].tokens.len(), 4);
+
+        rank(&mut hypotheses, 0.0);
+        assert_eq!(hypotheses[0].tokens.len(), 2);
+    }
+
+    #[test]
+    fn test_stop_match_finishes_the_hypothesis() {
+        let running = vec![hypothesis(vec![1], -0.1)];
+        let candidates = [(-0.2, 0, 7), (-0.5, 0, 3), (-0.9, 0, 4), (-1.2, 0, 7)];
+        let mut finished = Vec::new();
+
+        // Token 7 completes a stop sequence, it is kept as the end of the completion
+        let (next, parents) = select_candidates(&running, &candidates, 2, &mut finished, |_, token| {
+            Ok((Some(LlamaFinishReason::StopSequence).filter(|_| token == 7), ()))
+        })
+        .unwrap();
+
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].tokens, vec![1, 7]);
+        assert_eq!(finished[0].finish_reason, LlamaFinishReason::StopSequence);
+        assert_eq!(next.iter().map(|beam| beam.tokens.clone()).collect::<Vec<Vec<u32>>>(), vec![vec![1, 3], vec![1, 4]]);
+        assert_eq!(parents, vec![0, 0]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,244 @@
+// src/gateway/clients/candle/llama/chat.rs
+
+/// Candle API Llama Chat
+/// Chat requests made of system, user and assistant messages, rendered into a prompt with the
+/// model's Jinja chat template or a built-in Llama-2 or Llama-3 format.
+
+// Core Crates
+use minijinja::{context, Environment, Error as TemplateError, ErrorKind};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+// Candle Crates
+use tokenizers::{tokenizer::Tokenizer as HfTokenizer};
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::model_source::ModelRepo;
+use crate::gateway::clients::candle::llama::beam_search::LlamaBeamSearchParams;
+use crate::gateway::clients::candle::llama::context::LlamaTruncation;
+use crate::gateway::clients::candle::llama::generator::LlamaGenerateTextRequest;
+use crate::gateway::clients::candle::llama::sampling::SamplingParams;
+
+// [INST] format of the Llama-2 chat models, the system prompt is folded into the first user turn
+const LLAMA2_CHAT_TEMPLATE: &str = r#"
+{%- if messages[0]['role'] == 'system' -%}
+    {%- set system_message = messages[0]['content'] -%}
+    {%- set loop_messages = messages[1:] -%}
+{%- else -%}
+    {%- set system_message = none -%}
+    {%- set loop_messages = messages -%}
+{%- endif -%}
+{%- for message in loop_messages -%}
+    {%- if (message['role'] == 'user') != (loop.index0 % 2 == 0) -%}
+        {{ raise_exception('Conversation roles must alternate user/assistant/user/assistant/...') }}
+    {%- endif -%}
+    {%- if loop.index0 == 0 and system_message is not none -%}
+        {%- set content = '<<SYS>>\n' ~ system_message ~ '\n<</SYS>>\n\n' ~ message['content'] -%}
+    {%- else -%}
+        {%- set content = message['content'] -%}
+    {%- endif -%}
+    {%- if message['role'] == 'user' -%}
+        {{ bos_token ~ '[INST] ' ~ content | trim ~ ' [/INST]' }}
+    {%- elif message['role'] == 'assistant' -%}
+        {{ ' ' ~ content | trim ~ ' ' ~ eos_token }}
+    {%- endif -%}
+{%- endfor -%}
+"#;
+
+// Header format of the Llama-3 instruct models
+const LLAMA3_CHAT_TEMPLATE: &str = r#"
+{{- bos_token -}}
+{%- for message in messages -%}
+    {{- '<|start_header_id|>' ~ message['role'] ~ '<|end_header_id|>\n\n' ~ message['content'] | trim ~ '<|eot_id|>' -}}
+{%- endfor -%}
+{%- if add_generation_prompt -%}
+    {{- '<|start_header_id|>assistant<|end_header_id|>\n\n' -}}
+{%- endif -%}
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LlamaChatRole {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlamaChatMessage {
+    pub role: LlamaChatRole,
+    pub content: String,
+}
+
+// Same generation options as LlamaGenerateTextRequest, with messages in place of the raw prompt
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlamaChatRequest {
+    pub messages: Vec<LlamaChatMessage>,
+    #[serde(default)]
+    pub sampling: SamplingParams,
+    #[serde(default)]
+    pub stop: Vec<String>,
+    #[serde(default)]
+    pub logprobs: bool,
+    pub top_logprobs: Option<usize>,
+    #[serde(default)]
+    pub beam_search: Option<LlamaBeamSearchParams>,
+    pub n: Option<usize>,
+    pub truncation: Option<LlamaTruncation>,
+}
+
+impl LlamaChatRequest {
+    // Build the text generation request for an already rendered prompt
+    pub fn into_generate_request(self, prompt: String) -> LlamaGenerateTextRequest {
+        LlamaGenerateTextRequest {
+            prompt,
+            sampling: self.sampling,
+            stop: self.stop,
+            echo: false,
+            logprobs: self.logprobs,
+            top_logprobs: self.top_logprobs,
+            beam_search: self.beam_search,
+            n: self.n,
+            truncation: self.truncation,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LlamaChatTemplate {
+    template: String,
+    bos_token: String,
+    eos_token: String,
+}
+
+impl LlamaChatTemplate {
+    pub fn llama2() -> Self {
+        LlamaChatTemplate {
+            template: LLAMA2_CHAT_TEMPLATE.to_string(),
+            bos_token: "<s>".to_string(),
+            eos_token: "</s>".to_string(),
+        }
+    }
+
+    pub fn llama3() -> Self {
+        LlamaChatTemplate {
+            template: LLAMA3_CHAT_TEMPLATE.to_string(),
+            bos_token: "<|begin_of_text|>".to_string(),
+            eos_token: "<|eot_id|>".to_string(),
+        }
+    }
+
+    // Read the chat template and special tokens from tokenizer_config.json
+    // Checkpoints without a template fall back to the built-in format matching their vocabulary
+    pub async fn load(repo: &ModelRepo, tokenizer: &HfTokenizer) -> Self {
+        let fallback = if tokenizer.token_to_id("<|start_header_id|>").is_some() {
+            Self::llama3()
+        } else {
+            Self::llama2()
+        };
+
+        let config = match repo.get("tokenizer_config.json").await {
+            Ok(path) => fs::read(path).ok()
+                .and_then(|config| serde_json::from_slice::<serde_json::Value>(&config).ok()),
+            Err(_) => None,
+        };
+        let config = match config {
+            Some(config) => config,
+            None => return fallback,
+        };
+
+        // Special tokens are either plain strings or added-token objects
+        let special_token = |name: &str| match config.get(name) {
+            Some(serde_json::Value::String(token)) => Some(token.clone()),
+            Some(token) => token.get("content").and_then(|content| content.as_str()).map(|content| content.to_string()),
+            None => None,
+        };
+
+        // Some checkpoints ship several named templates, of which "default" is used
+        let template = match config.get("chat_template") {
+            Some(serde_json::Value::String(template)) => Some(template.clone()),
+            Some(serde_json::Value::Array(templates)) => templates.iter()
+                .find(|template| template.get("name").and_then(|name| name.as_str()) == Some("default"))
+                .and_then(|template| template.get("template"))
+                .and_then(|template| template.as_str())
+                .map(|template| template.to_string()),
+            _ => None,
+        };
+
+        LlamaChatTemplate {
+            template: template.unwrap_or(fallback.template),
+            bos_token: special_token("bos_token").unwrap_or(fallback.bos_token),
+            eos_token: special_token("eos_token").unwrap_or(fallback.eos_token),
+        }
+    }
+
+    // Render the conversation, optionally ending with the header that starts the assistant's reply
+    pub fn render(&self, messages: &[LlamaChatMessage], add_generation_prompt: bool) -> Result<String, CandleError> {
+        if messages.is_empty() {
+            return Err(CandleError::InvalidParameterError("messages must not be empty".into()));
+        }
+
+        // Templates written for Python Jinja call string methods such as strip()
+        let mut env = Environment::new();
+        env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+        env.add_function("raise_exception", raise_exception);
+        env.add_template("chat", &self.template)
+            .map_err(|err| CandleError::TemplateError(err.to_string()))?;
+
+        let prompt = env.get_template("chat")
+            .and_then(|template| template.render(context! {
+                messages => messages,
+                bos_token => &self.bos_token,
+                eos_token => &self.eos_token,
+                add_generation_prompt => add_generation_prompt,
+            }))
+            .map_err(|err| CandleError::TemplateError(err.to_string()))?;
+
+        // The tokenizer adds the BOS token itself, so a leading one from the template is dropped
+        match prompt.strip_prefix(self.bos_token.as_str()) {
+            Some(prompt) if !self.bos_token.is_empty() => Ok(prompt.to_string()),
+            _ => Ok(prompt),
+        }
+    }
+}
+
+fn raise_exception(message: String) -> Result<String, TemplateError> {
+    Err(TemplateError::new(ErrorKind::InvalidOperation, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: LlamaChatRole, content: &str) -> LlamaChatMessage {
+        LlamaChatMessage { role, content: content.to_string() }
+    }
+
+    #[test]
+    fn test_llama2_template_folds_system_prompt() {
+        let messages = vec![
+            message(LlamaChatRole::System, "Be brief."),
+            message(LlamaChatRole::User, "Hi"),
+            message(LlamaChatRole::Assistant, "Hello"),
+            message(LlamaChatRole::User, "Bye"),
+        ];
+
+        let prompt = LlamaChatTemplate::llama2().render(&messages, true).unwrap();
+        assert_eq!(prompt, "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello </s><s>[INST] Bye [/INST]");
+    }
+
+    #[test]
+    fn test_llama2_template_rejects_unordered_roles() {
+        let messages = vec![message(LlamaChatRole::Assistant, "Hello")];
+        assert!(LlamaChatTemplate::llama2().render(&messages, true).is_err());
+    }
+
+    #[test]
+    fn test_llama3_template_adds_generation_prompt() {
+        let messages = vec![message(LlamaChatRole::User, "Hi")];
+
+        let prompt = LlamaChatTemplate::llama3().render(&messages, true).unwrap();
+        assert_eq!(prompt, "<|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n");
+    }
+}
\ No newline at end of file
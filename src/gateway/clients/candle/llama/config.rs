@@ -8,6 +8,9 @@ use clap::Parser;
 use serde::{Deserialize, Serialize};
 
 // Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::model_source::{ModelRepo, ModelSource};
+use crate::gateway::clients::candle::llama::context::LlamaTruncation;
 use crate::gateway::clients::candle::SerializableDType;
 
 #[derive(Parser, Debug)]
@@ -37,7 +40,7 @@ struct Args {
     #[arg(long)]
     prompt: Option<String>,
 
-    /// Use different dtype than f16
+    /// Use a different dtype than the device default
     #[arg(long)]
     dtype: Option<String>,
 
@@ -69,9 +72,30 @@ pub struct LlamaModelConfig {
     pub dtype: Option<SerializableDType>,
     pub model_id: Option<String>,
     pub revision: Option<String>,
+    #[serde(default)]
+    pub model_source: ModelSource,
     pub use_flash_attn: bool,
     pub repeat_penalty: f32,
     pub repeat_last_n: usize,
+    // GGUF or GGML weights file, selects the quantized backend over the safetensors
+    #[serde(default)]
+    pub quantized_weights: Option<String>,
+    // Repository holding the quantized weights, the tokenizer is still read from model_id
+    #[serde(default)]
+    pub quantized_model_id: Option<String>,
+    // Grouped-query attention factor of GGML files, 8 for Llama-2 70B, GGUF files carry their own
+    #[serde(default)]
+    pub quantized_gqa: Option<usize>,
+    // How prompts longer than the context window are handled unless a request overrides it
+    #[serde(default)]
+    pub truncation: LlamaTruncation,
+    // Most inputs embedded in a single forward pass
+    #[serde(default = "default_embedding_batch_size")]
+    pub embedding_batch_size: usize,
+}
+
+fn default_embedding_batch_size() -> usize {
+    8
 }
 
 impl Default for LlamaModelConfig {
@@ -82,12 +106,35 @@ impl Default for LlamaModelConfig {
             top_p: Some(0.9), // default nucleus sampling probability cutoff
             seed: 299792458, // default seed
             sample_len: 100, // default sample length
-            dtype: Some(SerializableDType::F16), // default data type
+            dtype: None, // default data type is picked per device
             model_id: None, // model_id can be set later as needed
             revision: None, // revision can be set later as needed
+            model_source: ModelSource::Hub, // default to downloading from the hub
             use_flash_attn: false, // default attention mechanism
             repeat_penalty: 1.0, // default penalty for repeating tokens
             repeat_last_n: 64, // default context size for repeat penalty
+            quantized_weights: None, // default to full-precision safetensors
+            quantized_model_id: None, // quantized weights default to the model_id repository
+            quantized_gqa: None, // default to no grouped-query attention for GGML files
+            truncation: LlamaTruncation::Error, // default to rejecting over-long prompts
+            embedding_batch_size: default_embedding_batch_size(), // default inputs per embedding forward pass
+        }
+    }
+}
+
+impl LlamaModelConfig {
+    // Resolve where the model files are read from
+    pub fn model_repo(&self) -> Result<ModelRepo, CandleError> {
+        let model_id = self.model_id.clone().unwrap_or_else(|| "meta-llama/Llama-2-7b-hf".to_string());
+
+        ModelRepo::new(&self.model_source, &model_id, self.revision.as_deref())
+    }
+
+    // Resolve where the quantized weights are read from, the revision only applies to model_id
+    pub fn quantized_repo(&self) -> Result<ModelRepo, CandleError> {
+        match &self.quantized_model_id {
+            Some(model_id) => ModelRepo::new(&self.model_source, model_id, None),
+            None => self.model_repo(),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,143 @@
+// src/gateway/clients/candle/llama/context.rs
+
+/// Candle API Llama Context
+/// Fits prompts and sample lengths into the model's context window, truncating over-long
+/// prompts according to the configured policy.
+
+// Core Crates
+use serde::{Deserialize, Serialize};
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LlamaTruncation {
+    // Reject prompts that do not fit
+    #[default]
+    Error,
+    // Drop the oldest prompt tokens, keeping the BOS token
+    Left,
+    // Drop tokens from the middle of the prompt, keeping its start and end
+    MiddleOut,
+}
+
+// What was done to fit a request into the context window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlamaContextReport {
+    pub context_length: usize,
+    pub truncation: LlamaTruncation,
+    // Prompt tokens removed to make room for the completion
+    pub truncated_tokens: usize,
+    pub requested_sample_len: usize,
+    // sample_len after clamping to the remaining budget
+    pub sample_len: usize,
+}
+
+pub struct LlamaContextFit {
+    pub tokens: Vec<u32>,
+    pub sample_len: usize,
+    // Only set when the prompt or sample_len had to change
+    pub report: Option<LlamaContextReport>,
+}
+
+// Prompts that leave no room to generate are truncated so that up to half the window is left for
+// the completion, sample_len is then clamped to whatever budget remains
+pub fn fit_context(tokens: Vec<u32>, sample_len: usize, context_length: usize, truncation: LlamaTruncation) -> Result<LlamaContextFit, CandleError> {
+    if context_length == 0 {
+        return Err(CandleError::InvalidParameterError("context_length must be > 0".into()));
+    }
+
+    let prompt_len = tokens.len();
+    if prompt_len + sample_len <= context_length {
+        return Ok(LlamaContextFit { tokens, sample_len, report: None });
+    }
+
+    let tokens = if prompt_len >= context_length {
+        let keep = context_length - sample_len.min(context_length / 2);
+        match truncation {
+            LlamaTruncation::Error => {
+                return Err(CandleError::InvalidParameterError(format!("Prompt has {} tokens but the context window is {}", prompt_len, context_length)));
+            }
+            LlamaTruncation::Left => {
+                let mut kept = tokens[..1].to_vec();
+                kept.extend_from_slice(&tokens[prompt_len - (keep - 1)..]);
+                kept
+            }
+            LlamaTruncation::MiddleOut => {
+                let head = keep / 2;
+                let mut kept = tokens[..head].to_vec();
+                kept.extend_from_slice(&tokens[prompt_len - (keep - head)..]);
+                kept
+            }
+        }
+    } else {
+        tokens
+    };
+
+    let clamped_sample_len = sample_len.min(context_length - tokens.len());
+    let report = LlamaContextReport {
+        context_length,
+        truncation,
+        truncated_tokens: prompt_len - tokens.len(),
+        requested_sample_len: sample_len,
+        sample_len: clamped_sample_len,
+    };
+
+    Ok(LlamaContextFit {
+        tokens,
+        sample_len: clamped_sample_len,
+        report: Some(report),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fitting_request_is_untouched() {
+        let fit = fit_context(vec![1, 2, 3], 5, 8, LlamaTruncation::Error).unwrap();
+        assert_eq!(fit.tokens, vec![1, 2, 3]);
+        assert_eq!(fit.sample_len, 5);
+        assert!(fit.report.is_none());
+    }
+
+    #[test]
+    fn test_prompt_filling_the_window_fits_without_sample_len() {
+        let fit = fit_context(vec![1, 2, 3, 4], 0, 4, LlamaTruncation::Left).unwrap();
+        assert_eq!(fit.tokens, vec![1, 2, 3, 4]);
+        assert!(fit.report.is_none());
+    }
+
+    #[test]
+    fn test_sample_len_is_clamped() {
+        let fit = fit_context(vec![1, 2, 3], 10, 8, LlamaTruncation::Error).unwrap();
+        assert_eq!(fit.sample_len, 5);
+        assert_eq!(fit.report.map(|report| report.truncated_tokens), Some(0));
+    }
+
+    #[test]
+    fn test_empty_context_window_is_rejected() {
+        assert!(fit_context(vec![1, 2, 3], 1, 0, LlamaTruncation::Left).is_err());
+    }
+
+    #[test]
+    fn test_over_long_prompt_is_rejected() {
+        assert!(fit_context((0..10).collect(), 4, 8, LlamaTruncation::Error).is_err());
+    }
+
+    #[test]
+    fn test_left_truncation_keeps_bos() {
+        let fit = fit_context((0..10).collect(), 2, 8, LlamaTruncation::Left).unwrap();
+        assert_eq!(fit.tokens, vec![0, 5, 6, 7, 8, 9]);
+        assert_eq!(fit.sample_len, 2);
+    }
+
+    #[test]
+    fn test_middle_out_keeps_both_ends() {
+        let fit = fit_context((0..10).collect(), 2, 8, LlamaTruncation::MiddleOut).unwrap();
+        assert_eq!(fit.tokens, vec![0, 1, 2, 7, 8, 9]);
+        assert_eq!(fit.report.map(|report| report.truncated_tokens), Some(4));
+    }
+}
\ No newline at end of file
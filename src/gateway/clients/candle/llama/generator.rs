@@ -5,17 +5,599 @@
 
 // Core Crates
 use serde::{Deserialize, Serialize};
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+// Candle Crates
+use candle_core::Tensor;
+use candle_transformers::models::quantized_llama::ModelWeights;
 
 // Networking Crates
-use crate::gateway::clients::candle::llama::config::LlamaModelConfig;
+use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::logits::{log_softmax, top_n, LogitsPipeline};
+use crate::gateway::clients::candle::pooling::Pooling;
+use crate::gateway::clients::candle::llama::beam_search::{LlamaBeam, LlamaBeamSearchParams};
+use crate::gateway::clients::candle::llama::context::{fit_context, LlamaContextReport, LlamaTruncation};
+use crate::gateway::clients::candle::llama::model::LlamaModel;
+use crate::gateway::clients::candle::llama::sampling::{ResolvedSamplingParams, SamplingParams};
+use crate::gateway::clients::candle::llama::tokenizer::LlamaTokenOutputStream;
+use crate::gateway::clients::candle::llama::transformer::LlamaCache;
+
+// Most completions sampled for a single request
+const MAX_COMPLETIONS: usize = 16;
 
 #[derive(Debug, Serialize, serde::Deserialize)]
 pub struct LlamaGenerateTextRequest {
     pub prompt: String,
-    pub config: LlamaModelConfig,
+    // Overrides for the model's default sampling configuration
+    #[serde(default)]
+    pub sampling: SamplingParams,
+    // Stop generating once any of these strings appears in the completion
+    #[serde(default)]
+    pub stop: Vec<String>,
+    // Return the decoded prompt alongside the completion
+    #[serde(default)]
+    pub echo: bool,
+    // Return the log-probability of every generated token
+    #[serde(default)]
+    pub logprobs: bool,
+    // Number of most likely alternatives to return per token when logprobs is set
+    pub top_logprobs: Option<usize>,
+    // Decode with beam search instead of sampling, only supported by generate_text
+    #[serde(default)]
+    pub beam_search: Option<LlamaBeamSearchParams>,
+    // Number of independent completions to sample, only supported by generate_text
+    pub n: Option<usize>,
+    // How to handle prompts longer than the context window, defaulting to the model config
+    pub truncation: Option<LlamaTruncation>,
+}
+
+impl LlamaGenerateTextRequest {
+    // Number of completions requested, defaulting to one
+    pub fn completions(&self) -> Result<usize, CandleError> {
+        let n = self.n.unwrap_or(1);
+        if !(1..=MAX_COMPLETIONS).contains(&n) {
+            return Err(CandleError::InvalidParameterError(format!("n must be in [1, {}], got {}", MAX_COMPLETIONS, n)));
+        }
+
+        Ok(n)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LlamaGenerateTextResponse {
     pub generated_text: String,
+    pub prompt: Option<String>,
+    pub finish_reason: LlamaFinishReason,
+    pub usage: LlamaUsage,
+    pub timing: LlamaTiming,
+    pub logprobs: Option<Vec<LlamaTokenLogprob>>,
+    // The n-best completions when decoding with beam search
+    pub beams: Option<Vec<LlamaBeam>>,
+    // Every completion when n > 1, the top-level fields mirror the first one
+    pub choices: Option<Vec<LlamaChoice>>,
+    // Set when the prompt was truncated or sample_len clamped to fit the context window
+    pub context: Option<LlamaContextReport>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlamaChoice {
+    pub index: usize,
+    pub text: String,
+    pub finish_reason: LlamaFinishReason,
+    pub completion_tokens: usize,
+    pub logprobs: Option<Vec<LlamaTokenLogprob>>,
+}
+
+impl LlamaChoice {
+    pub fn new(index: usize, response: LlamaGenerateTextResponse) -> Self {
+        LlamaChoice {
+            index,
+            text: response.generated_text,
+            finish_reason: response.finish_reason,
+            completion_tokens: response.usage.completion_tokens,
+            logprobs: response.logprobs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlamaTokenLogprob {
+    pub token_id: u32,
+    pub text: String,
+    pub logprob: f32,
+    pub top_logprobs: Vec<LlamaTopLogprob>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlamaTopLogprob {
+    pub token_id: u32,
+    pub text: String,
+    pub logprob: f32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LlamaUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl LlamaUsage {
+    pub fn new(prompt_tokens: usize, completion_tokens: usize) -> Self {
+        LlamaUsage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+}
+
+// Wall-clock timings in milliseconds
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LlamaTiming {
+    pub prompt_duration_ms: u64,
+    pub generation_duration_ms: u64,
+    pub total_duration_ms: u64,
+}
+
+impl LlamaTiming {
+    // The prompt duration is unknown when no token was generated, in which case it spans the whole call
+    pub fn new(prompt_duration: Option<Duration>, total_duration: Duration) -> Self {
+        let prompt_duration = prompt_duration.unwrap_or(total_duration);
+
+        LlamaTiming {
+            prompt_duration_ms: prompt_duration.as_millis() as u64,
+            generation_duration_ms: total_duration.saturating_sub(prompt_duration).as_millis() as u64,
+            total_duration_ms: total_duration.as_millis() as u64,
+        }
+    }
+}
+
+// Score text without sampling
+// With a continuation only its tokens are scored, conditioned on the prompt
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlamaScoreRequest {
+    pub prompt: String,
+    pub continuation: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlamaScoreResponse {
+    pub tokens: Vec<LlamaTokenScore>,
+    pub total_logprob: f32,
+    pub perplexity: f32,
+    pub usage: LlamaUsage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlamaTokenScore {
+    pub token_id: u32,
+    pub text: String,
+    pub logprob: f32,
+}
+
+// Embed a batch of inputs from the final hidden layer
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlamaEmbeddingRequest {
+    pub inputs: Vec<String>,
+    #[serde(default)]
+    pub pooling: Pooling,
+    // Scale every embedding to unit length
+    #[serde(default)]
+    pub normalize: bool,
+    // How inputs longer than the context window are handled, defaulting to the model config
+    pub truncation: Option<LlamaTruncation>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LlamaEmbeddingResponse {
+    // One embedding per input, in request order
+    pub embeddings: Vec<Vec<f32>>,
+    pub usage: LlamaUsage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LlamaFinishReason {
+    Length,
+    Eos,
+    StopSequence,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum LlamaStreamEvent {
+    Token {
+        text: String,
+    },
+    Done {
+        finish_reason: LlamaFinishReason,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        // Set when the prompt was truncated or sample_len clamped to fit the context window
+        context: Option<LlamaContextReport>,
+    },
+}
+
+// Sampling and decoding state of a single sequence, independent of how its logits are computed
+pub struct LlamaSequence<'a> {
+    model: &'a LlamaModel,
+    params: ResolvedSamplingParams,
+    logits_pipeline: LogitsPipeline,
+    tokens: Vec<u32>,
+    prompt_tokens: usize,
+    echo: bool,
+    finish_reason: Option<LlamaFinishReason>,
+    output: LlamaTokenOutputStream<'a>,
+    stop_sequences: LlamaStopSequences,
+    top_logprobs: usize,
+    logprobs: Option<Vec<LlamaTokenLogprob>>,
+    context: Option<LlamaContextReport>,
+}
+
+impl<'a> LlamaSequence<'a> {
+    pub fn new(model: &'a LlamaModel, request: &LlamaGenerateTextRequest) -> Result<Self, CandleError> {
+        if request.completions()? > 1 {
+            return Err(CandleError::InvalidParameterError("n > 1 is only supported by generate_text".into()));
+        }
+
+        let tokens = model.tokenizer.encode(&request.prompt, true)?;
+
+        Self::from_tokens(model, request, tokens)
+    }
+
+    // Build a sequence from an already encoded prompt, so several sequences can share one encoding
+    pub fn from_tokens(model: &'a LlamaModel, request: &LlamaGenerateTextRequest, tokens: Vec<u32>) -> Result<Self, CandleError> {
+        // Validate the request before doing any work
+        let mut params = request.sampling.resolve(&model.config)?;
+        if request.stop.iter().any(|stop| stop.is_empty()) {
+            return Err(CandleError::InvalidParameterError("stop sequences must not be empty".into()));
+        }
+        if request.beam_search.is_some() {
+            return Err(CandleError::InvalidParameterError("beam_search is only supported by generate_text".into()));
+        }
+        let top_logprobs = request.top_logprobs.unwrap_or(0);
+        if top_logprobs > 20 {
+            return Err(CandleError::InvalidParameterError(format!("top_logprobs must be <= 20, got {}", top_logprobs)));
+        }
+        if request.top_logprobs.is_some() && !request.logprobs {
+            return Err(CandleError::InvalidParameterError("top_logprobs requires logprobs to be set".into()));
+        }
+
+        // Make the prompt and completion fit the context window
+        let truncation = request.truncation.unwrap_or(model.config.truncation);
+        let fit = fit_context(tokens, params.sample_len, model.context_length()?, truncation)?;
+        params.sample_len = fit.sample_len;
+        let tokens = fit.tokens;
+        let prompt_tokens = tokens.len();
+
+        // Build the logits pipeline with the configuration from the request
+        let logits_pipeline = params.logits_pipeline(prompt_tokens);
+        let output = model.tokenizer.output_stream_after(&tokens);
+
+        Ok(LlamaSequence {
+            model,
+            params,
+            logits_pipeline,
+            tokens,
+            prompt_tokens,
+            echo: request.echo,
+            finish_reason: None,
+            output,
+            stop_sequences: LlamaStopSequences::new(request.stop.clone()),
+            top_logprobs,
+            logprobs: if request.logprobs { Some(Vec::new()) } else { None },
+            context: fit.report,
+        })
+    }
+
+    // Shift the seed so completions of the same prompt sample differently
+    pub fn offset_seed(&mut self, offset: u64) {
+        self.params.seed = self.params.seed.wrapping_add(offset);
+        self.logits_pipeline = self.params.logits_pipeline(self.prompt_tokens);
+    }
+
+    // Check whether the sequence is done, marking it finished once sample_len is reached
+    pub fn is_finished(&mut self) -> bool {
+        if self.finish_reason.is_none() && self.completion_tokens() >= self.params.sample_len {
+            self.finish_reason = Some(LlamaFinishReason::Length);
+        }
+
+        self.finish_reason.is_some()
+    }
+
+    // Sample the next token from this sequence's logits, returning None at end of sequence
+    pub fn sample(&mut self, logits: &Tensor) -> Result<Option<u32>, CandleError> {
+        let logits = self.logits_pipeline.process(logits, &self.tokens)?;
+        let next_token = self.logits_pipeline.sample_processed(&logits)?;
+        self.record_logprobs(&logits, next_token)?;
+        self.tokens.push(next_token);
+
+        // Check for end-of-sequence token and stop if found
+        if self.model.tokenizer.is_eos_token(next_token) {
+            self.finish_reason = Some(LlamaFinishReason::Eos);
+            return Ok(None);
+        }
+
+        Ok(Some(next_token))
+    }
+
+    // Decode the text completed by the latest token, minus anything that may start a stop sequence
+    pub fn decode_next(&mut self, token: u32) -> Result<Option<String>, CandleError> {
+        let text = match self.output.next_token(token)? {
+            Some(text) => text,
+            None => return Ok(None),
+        };
+
+        Ok(self.check_stop_sequences(&text))
+    }
+
+    // Flush any text still held back once generation has finished
+    pub fn decode_rest(&mut self) -> Result<Option<String>, CandleError> {
+        // Text after a matched stop sequence is discarded
+        if self.finish_reason == Some(LlamaFinishReason::StopSequence) {
+            return Ok(None);
+        }
+
+        let mut text = match self.output.decode_rest()? {
+            Some(rest) => self.check_stop_sequences(&rest).unwrap_or_default(),
+            None => String::new(),
+        };
+        if self.finish_reason != Some(LlamaFinishReason::StopSequence) {
+            text.push_str(&self.stop_sequences.flush());
+        }
+
+        Ok(Some(text).filter(|text| !text.is_empty()))
+    }
+
+    // Log-probabilities come from the softmax of the processed logits the token was sampled from
+    // Tokens removed by the filtering stages have no probability and are left out of top_logprobs
+    fn record_logprobs(&mut self, logits: &[f32], token: u32) -> Result<(), CandleError> {
+        let logprobs = match self.logprobs.as_mut() {
+            Some(logprobs) => logprobs,
+            None => return Ok(()),
+        };
+
+        let tokenizer = &self.model.tokenizer;
+        let token_logprobs = log_softmax(logits);
+
+        let mut top_logprobs = Vec::with_capacity(self.top_logprobs);
+        let candidates = top_n(&token_logprobs, self.top_logprobs)
+            .into_iter()
+            .filter(|(_, logprob)| logprob.is_finite());
+        for (token_id, logprob) in candidates {
+            top_logprobs.push(LlamaTopLogprob {
+                token_id,
+                text: tokenizer.decode(&[token_id], false)?,
+                logprob,
+            });
+        }
+
+        logprobs.push(LlamaTokenLogprob {
+            token_id: token,
+            text: tokenizer.decode(&[token], false)?,
+            logprob: token_logprobs[token as usize],
+            top_logprobs,
+        });
+
+        Ok(())
+    }
+
+    fn check_stop_sequences(&mut self, text: &str) -> Option<String> {
+        let (text, stopped) = self.stop_sequences.push(text);
+        if stopped {
+            self.finish_reason = Some(LlamaFinishReason::StopSequence);
+        }
+
+        Some(text).filter(|text| !text.is_empty())
+    }
+
+    // Build the response once the sequence has finished
+    pub fn response(&mut self, generated_text: String, timing: LlamaTiming) -> Result<LlamaGenerateTextResponse, CandleError> {
+        let prompt = if self.echo {
+            Some(self.model.tokenizer.decode(&self.tokens[..self.prompt_tokens], true)?)
+        } else {
+            None
+        };
+
+        Ok(LlamaGenerateTextResponse {
+            generated_text,
+            prompt,
+            finish_reason: self.finish_reason.unwrap_or(LlamaFinishReason::Length),
+            usage: LlamaUsage::new(self.prompt_tokens, self.completion_tokens()),
+            timing,
+            logprobs: self.logprobs.take(),
+            beams: None,
+            choices: None,
+            context: self.context.clone(),
+        })
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn prompt_tokens(&self) -> usize {
+        self.prompt_tokens
+    }
+
+    pub fn completion_tokens(&self) -> usize {
+        self.tokens.len() - self.prompt_tokens
+    }
+
+    // Tokens left before sample_len is reached
+    pub fn remaining_tokens(&self) -> usize {
+        self.params.sample_len.saturating_sub(self.completion_tokens())
+    }
+
+    // Upper bound on the tokens this sequence holds in the cache once finished
+    pub fn reserved_tokens(&self) -> usize {
+        self.prompt_tokens + self.params.sample_len
+    }
+
+    pub fn finish_reason(&self) -> Option<LlamaFinishReason> {
+        self.finish_reason
+    }
+
+    pub fn context(&self) -> Option<&LlamaContextReport> {
+        self.context.as_ref()
+    }
+}
+
+// Forward state owned by a single generation
+enum LlamaGenerationBackend {
+    Full(LlamaCache),
+    // Quantized weights keep their kv cache inside the model, so each generation runs on its own clone
+    // The clone is cheap since the quantized tensors are shared
+    Quantized(ModelWeights),
+}
+
+// Step-by-step generation of a single sequence, shared by the blocking and streaming APIs
+pub struct LlamaGeneration<'a> {
+    sequence: LlamaSequence<'a>,
+    backend: LlamaGenerationBackend,
+    index_pos: usize,
+}
+
+impl<'a> LlamaGeneration<'a> {
+    pub fn new(model: &'a LlamaModel, request: &LlamaGenerateTextRequest) -> Result<Self, CandleError> {
+        let sequence = LlamaSequence::new(model, request)?;
+
+        // Each request gets its own kv cache so concurrent generations don't share state
+        let backend = match &model.quantized {
+            Some(weights) => LlamaGenerationBackend::Quantized(weights.clone()),
+            None => {
+                // Ensure model is initialized
+                let llama_config = model.llama_config.as_ref().ok_or(CandleError::UninitializedModelError)?;
+                let device = model.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+                let dtype = model.dtype.ok_or(CandleError::UninitializedModelError)?;
+
+                LlamaGenerationBackend::Full(LlamaCache::new(true, dtype, llama_config, device)?)
+            }
+        };
+
+        Ok(LlamaGeneration {
+            sequence,
+            backend,
+            index_pos: 0,
+        })
+    }
+
+    // Sample the next token, returning None once generation has finished
+    pub fn next_token(&mut self) -> Result<Option<u32>, CandleError> {
+        if self.sequence.is_finished() {
+            return Ok(None);
+        }
+
+        let device = self.sequence.model.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+
+        // The first step processes the whole prompt, later steps only feed the newest token
+        let tokens = self.sequence.tokens();
+        let context_size = if self.index_pos > 0 { 1 } else { tokens.len() };
+        let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
+        let input = Tensor::new(ctxt, device)?.unsqueeze(0)?;
+        let logits = match &mut self.backend {
+            LlamaGenerationBackend::Full(cache) => {
+                let model = self.sequence.model.model.as_ref().ok_or(CandleError::UninitializedModelError)?;
+                model.forward(&input, self.index_pos, cache, None)?
+            }
+            LlamaGenerationBackend::Quantized(weights) => weights.forward(&input, self.index_pos)?,
+        };
+        let logits = logits.squeeze(0)?;
+
+        self.index_pos += ctxt.len();
+
+        self.sequence.sample(&logits)
+    }
+}
+
+impl<'a> Deref for LlamaGeneration<'a> {
+    type Target = LlamaSequence<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.sequence
+    }
+}
+
+impl<'a> DerefMut for LlamaGeneration<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.sequence
+    }
+}
+
+// Matches stop sequences against decoded text as it arrives
+// Text that could still turn into a stop sequence is held back until it is ruled out
+#[derive(Clone)]
+pub struct LlamaStopSequences {
+    stop: Vec<String>,
+    pending: String,
+}
+
+impl LlamaStopSequences {
+    pub fn new(stop: Vec<String>) -> Self {
+        LlamaStopSequences {
+            stop,
+            pending: String::new(),
+        }
+    }
+
+    // Returns the text safe to emit and whether a stop sequence was matched
+    pub fn push(&mut self, text: &str) -> (String, bool) {
+        self.pending.push_str(text);
+
+        // Stop at the earliest match, trimming the stop sequence and everything after it
+        let matched = self.stop.iter()
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(position) = matched {
+            let emitted = self.pending[..position].to_string();
+            self.pending.clear();
+            return (emitted, true);
+        }
+
+        // Hold back the longest suffix that is a prefix of some stop sequence
+        let held_from = self.pending.char_indices()
+            .map(|(index, _)| index)
+            .find(|&index| {
+                let suffix = &self.pending[index..];
+                self.stop.iter().any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(self.pending.len());
+
+        let emitted = self.pending[..held_from].to_string();
+        self.pending.drain(..held_from);
+
+        (emitted, false)
+    }
+
+    // Release held back text once no more tokens will arrive
+    pub fn flush(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_sequence_across_chunks() {
+        let mut stop_sequences = LlamaStopSequences::new(vec!["\n\nUser:".to_string()]);
+
+        assert_eq!(stop_sequences.push("Hello"), ("Hello".to_string(), false));
+        assert_eq!(stop_sequences.push(" there\n"), (" there".to_string(), false));
+        assert_eq!(stop_sequences.push("\nUs"), (String::new(), false));
+        assert_eq!(stop_sequences.push("er: hi"), (String::new(), true));
+    }
+
+    #[test]
+    fn test_held_back_text_is_released() {
+        let mut stop_sequences = LlamaStopSequences::new(vec!["###".to_string()]);
+
+        assert_eq!(stop_sequences.push("a #"), ("a ".to_string(), false));
+        assert_eq!(stop_sequences.push("b"), ("#b".to_string(), false));
+        assert_eq!(stop_sequences.push("#"), (String::new(), false));
+        assert_eq!(stop_sequences.flush(), "#");
+    }
 }
\ No newline at end of file
@@ -4,7 +4,15 @@
 /// The main module file for the llama submodule, which orchestrates the initialization and
 /// interaction between the config, model, tokenizer, and generator.
 
+pub mod batch;
+pub mod beam_search;
+pub mod chat;
 pub mod config;
+pub mod context;
 pub mod generator;
 pub mod model;
-pub mod tokenizer;
\ No newline at end of file
+pub mod quantized;
+pub mod sampling;
+pub mod scheduler;
+pub mod tokenizer;
+pub mod transformer;
\ No newline at end of file
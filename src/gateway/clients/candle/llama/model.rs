@@ -4,25 +4,54 @@
 /// Contains code related to downloading weights and initializing the LlamaModel.
 
 // Core Crates
+use std::collections::BTreeSet;
+use std::fs;
 use std::option::Option;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 
 // Candle Crates
-use candle_core::{Device, DType, Tensor};
+use candle_core::{Device, DType, Tensor, D};
+use candle_nn::ops::log_softmax;
 use candle_nn::var_builder::VarBuilder;
-use hf_hub::api::tokio::Api;
+use futures::stream::{self, Stream};
 
 use candle_transformers::models::llama as model;
-use model::{Config, Llama};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use model::{Config, LlamaConfig};
 use tokenizers::Tokenizer;
 
 // Networking Crates
 use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::pooling::{normalize_l2, pool, Pooling};
+use crate::gateway::clients::candle::{select_device, select_dtype};
+use crate::gateway::clients::candle::llama::batch::{fits_prefill, LlamaBatch, PAD_TOKEN_ID};
+use crate::gateway::clients::candle::llama::beam_search::LlamaBeamSearch;
+use crate::gateway::clients::candle::llama::chat::LlamaChatRequest;
 use crate::gateway::clients::candle::llama::config::LlamaModelConfig;
-use crate::gateway::clients::candle::llama::generator::{LlamaGenerateTextRequest, LlamaGenerateTextResponse};
+use crate::gateway::clients::candle::llama::context::fit_context;
+use crate::gateway::clients::candle::llama::generator::{
+    LlamaChoice, LlamaEmbeddingRequest, LlamaEmbeddingResponse, LlamaFinishReason, LlamaGenerateTextRequest, LlamaGenerateTextResponse, LlamaGeneration, LlamaScoreRequest,
+    LlamaScoreResponse, LlamaSequence, LlamaStreamEvent, LlamaTiming, LlamaTokenScore, LlamaUsage,
+};
+use crate::gateway::clients::candle::llama::quantized::load_quantized_weights;
 use crate::gateway::clients::candle::llama::tokenizer::LlamaTokenizer;
+use crate::gateway::clients::candle::llama::transformer::{LlamaCache, LlamaTransformer};
+
+// Events a streaming generation may run ahead of its consumer
+const STREAM_BUFFER_SIZE: usize = 16;
 
 pub struct LlamaModel {
-    pub model: Option<Llama>,
+    pub model: Option<LlamaTransformer>,
+    // Set instead of model when running quantized weights
+    pub quantized: Option<ModelWeights>,
+    // Context length of the quantized weights, from the GGUF metadata
+    pub quantized_context_length: Option<usize>,
+    pub llama_config: Option<Config>,
+    pub device: Option<Device>,
+    pub dtype: Option<DType>,
     pub config: LlamaModelConfig,
     pub tokenizer: LlamaTokenizer,
 }
@@ -31,107 +60,487 @@ impl LlamaModel {
     pub fn new(config: LlamaModelConfig, tokenizer: LlamaTokenizer) -> Self {
         LlamaModel {
             model: None,
+            quantized: None,
+            quantized_context_length: None,
+            llama_config: None,
+            device: None,
+            dtype: None,
             config,
             tokenizer,
         }
     }
 
-    pub async fn download_weights(&self) -> Result<String, CandleError> {
-        let api = Api::new().map_err(|_| CandleError::InitializationError)?;
-        let model_id = self.config.model_id.clone().unwrap_or_else(|| "meta-llama/Llama-2-7b-hf".to_string());
-        let repo = api.model(model_id);
+    pub fn is_quantized(&self) -> bool {
+        self.config.quantized_weights.is_some()
+    }
+
+    // Longest sequence the model attends over, prompt and completion together
+    pub fn context_length(&self) -> Result<usize, CandleError> {
+        if self.is_quantized() {
+            return self.quantized_context_length.ok_or(CandleError::UninitializedModelError);
+        }
+
+        self.llama_config
+            .as_ref()
+            .map(|llama_config| llama_config.max_position_embeddings)
+            .ok_or(CandleError::UninitializedModelError)
+    }
 
-        let weights_filename = repo.get("model.safetensors").await
-            .map_err(|_| CandleError::DownloadError)?;
+    // Features that need the full-precision transformer, such as batching or scoring
+    pub fn require_full_precision(&self, feature: &str) -> Result<(), CandleError> {
+        if self.is_quantized() {
+            return Err(CandleError::InvalidParameterError(format!("{} is not supported by the quantized backend", feature)));
+        }
 
-        Ok(weights_filename.to_string_lossy().into_owned())
+        Ok(())
+    }
+
+    // Download the model weights, fetching every shard when the checkpoint is split
+    pub async fn download_weights(&self) -> Result<Vec<PathBuf>, CandleError> {
+        // Quantized weights come as a single GGUF or GGML file
+        if let Some(quantized_weights) = &self.config.quantized_weights {
+            let repo = self.config.quantized_repo()?;
+            println!("Downloading quantized weights {}...", quantized_weights);
+
+            return Ok(vec![repo.get(quantized_weights).await?]);
+        }
+
+        let repo = self.config.model_repo()?;
+
+        // Sharded checkpoints ship an index mapping each tensor to its shard, single-file ones do not
+        let index_filename = match repo.find("model.safetensors.index.json").await? {
+            Some(index_filename) => index_filename,
+            None => {
+                let weights_filename = repo.get("model.safetensors").await?;
+
+                return Ok(vec![weights_filename]);
+            }
+        };
+
+        let mut weights_filenames = Vec::new();
+        for shard in Self::load_shard_names(&index_filename)? {
+            println!("Downloading weights shard {}...", shard);
+            let shard_filename = repo.get(&shard).await?;
+            weights_filenames.push(shard_filename);
+        }
+
+        Ok(weights_filenames)
+    }
+
+    // Collect the unique shard filenames listed in model.safetensors.index.json
+    fn load_shard_names(index_path: &Path) -> Result<BTreeSet<String>, CandleError> {
+        let index_file = fs::read(index_path)
+            .map_err(|_| CandleError::LoadModelError("Failed to read model.safetensors.index.json".into()))?;
+        let index: serde_json::Value = serde_json::from_slice(&index_file)
+            .map_err(|_| CandleError::LoadModelError("Failed to parse model.safetensors.index.json".into()))?;
+
+        let weight_map = index.get("weight_map")
+            .and_then(|weight_map| weight_map.as_object())
+            .ok_or_else(|| CandleError::LoadModelError("No weight_map in model.safetensors.index.json".into()))?;
+
+        let shards = weight_map.values()
+            .filter_map(|shard| shard.as_str())
+            .map(|shard| shard.to_string())
+            .collect::<BTreeSet<String>>();
+
+        Ok(shards)
+    }
+
+    pub async fn download_config(&self) -> Result<PathBuf, CandleError> {
+        let repo = self.config.model_repo()?;
+
+        repo.get("config.json").await
+    }
+
+    // Parse the repository's config.json into the Candle Llama config
+    pub fn load_config(&self, config_path: &Path) -> Result<Config, CandleError> {
+        let config_file = fs::read(config_path)
+            .map_err(|_| CandleError::LoadModelError("Failed to read config.json".into()))?;
+        let llama_config: LlamaConfig = serde_json::from_slice(&config_file)
+            .map_err(|_| CandleError::LoadModelError("Failed to parse config.json".into()))?;
+
+        Ok(llama_config.into_config(self.config.use_flash_attn))
     }
 
     pub fn get_tokenizer(&self) -> Result<&Tokenizer, CandleError> {
         self.tokenizer.ok_or(CandleError::UninitializedModelError)
     }
 
-    pub async fn initialize_model(&mut self, weights_path: &str) -> Result<(), CandleError> {
+    pub async fn initialize_model(&mut self, weights_paths: &[PathBuf]) -> Result<(), CandleError> {
 
         println!("Building Llama tokenizer...");
         self.tokenizer.download_and_load_tokenizer().await?;
 
-        let device = Device::new_cuda(0)?;
-        let dtype = DType::F16;
-        let config = Config::config_7b_v2(false);
+        let device = select_device(self.config.cpu)?;
+        println!("Running Llama model on {:?}", device);
 
-        let cache = model::Cache::new(false, dtype, &config, &device)?;
-        let weights = candle_core::safetensors::load(weights_path, &device)
-            .map_err(|_| CandleError::LoadModelError);
-        let vb = VarBuilder::from_tensors(weights, dtype, &device);
+        if self.is_quantized() {
+            let weights_path = weights_paths.first()
+                .ok_or_else(|| CandleError::LoadModelError("No quantized weights file".into()))?;
+
+            // Quantized weights keep their own precision and attention kernels
+            if self.config.dtype.is_some() || self.config.use_flash_attn {
+                return Err(CandleError::InvalidParameterError("dtype and use_flash_attn do not apply to quantized weights".into()));
+            }
+
+            println!("Building quantized Llama model...");
+            let gqa = self.config.quantized_gqa.unwrap_or(1);
+            let (weights, context_length) = load_quantized_weights(weights_path, gqa, &device)?;
+            println!("Quantized Llama context length is {}", context_length);
+            self.quantized = Some(weights);
+            self.quantized_context_length = Some(context_length);
+            self.device = Some(device);
+
+            return Ok(());
+        }
+
+        // Flash attention needs the flash-attn feature at build time
+        if self.config.use_flash_attn && !cfg!(feature = "flash-attn") {
+            return Err(CandleError::InvalidParameterError("use_flash_attn requires building with the flash-attn feature".into()));
+        }
+
+        let dtype = select_dtype(self.config.dtype, &device, self.config.use_flash_attn)?;
+        println!("Loading Llama weights as {:?}", dtype);
+
+        println!("Loading Llama config...");
+        let config_path = self.download_config().await?;
+        let config = self.load_config(&config_path)?;
+
+        // Memory-map every shard so weights are paged in lazily rather than copied up front
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(weights_paths, dtype, &device)? };
 
         println!("Building Llama model...");
-        let model = Llama::load(vb, &cache, &config)?;
+        let model = LlamaTransformer::load(vb, &config)?;
         self.model = Some(model);
+        self.llama_config = Some(config);
+        self.device = Some(device);
+        self.dtype = Some(dtype);
 
         Ok(())
     }
 
     pub async fn generate_text(&self, request: LlamaGenerateTextRequest) -> Result<LlamaGenerateTextResponse, CandleError> {
-        use candle_transformers::generation::LogitsProcessor;
-
-        // Ensure model is initialized
-        let model = self.model.as_ref().ok_or(CandleError::UninitializedModelError);
-        let device = Device::new_cuda(0)?;
-        let tokenizer = &self.tokenizer;
+        if request.beam_search.is_some() {
+            return LlamaBeamSearch::new(self, &request)?.run();
+        }
+        let n = request.completions()?;
+        if n > 1 {
+            return self.generate_text_n(request, n);
+        }
 
         // Start the generation process
         println!("Starting the text generation...");
-        let prompt = &request.prompt;
-        let mut tokens = self.tokenizer.encode(prompt, true)?;
-
-        // Initialize the logits processor with the configuration from the request
-        let mut logits_processor = LogitsProcessor::new(
-            self.config.seed,
-            Some(self.config.temperature.unwrap_or(1.0)),
-            Some(self.config.top_p.unwrap_or(0.9)),
-        );
-
-        let mut index_pos = 0;
-        let mut token_generated = 0;
-        for _ in 0..self.config.sample_len {
-            let context_size = tokens.len(); // Assuming no kv_cache for simplicity
-            let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-            let input = Tensor::new(ctxt, &device)?.unsqueeze(0)?;
-            let logits = model.forward(&input)?;
-            let logits = logits.squeeze(0)?;
-
-            // Apply repeat penalty if configured
-            let logits = if self.config.repeat_penalty != 1.0 {
-                let start_at = tokens.len().saturating_sub(self.config.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    self.config.repeat_penalty,
-                    &tokens[start_at..],
-                )?
-            } else {
-                logits
-            };
-
-            index_pos += ctxt.len();
-
-            let next_token = logits_processor.sample(&logits)?;
-            token_generated += 1;
-            tokens.push(next_token);
-
-            // Check for end-of-sequence token and break if found
-            if let Some(eos_token_id) = tokenizer.token_to_id("<|endoftext|>") {
-                if Some(next_token) == eos_token_id {
-                    break;
+        let start = Instant::now();
+        let mut generation = LlamaGeneration::new(self, &request)?;
+        let mut generated_text = String::new();
+        let mut prompt_duration = None;
+
+        // Decode incrementally so word boundaries and multi-byte characters survive
+        while let Some(token) = generation.next_token()? {
+            // The first step includes the forward pass over the whole prompt
+            prompt_duration.get_or_insert_with(|| start.elapsed());
+
+            if let Some(text) = generation.decode_next(token)? {
+                generated_text.push_str(&text);
+            }
+        }
+        if let Some(text) = generation.decode_rest()? {
+            generated_text.push_str(&text);
+        }
+
+        let timing = LlamaTiming::new(prompt_duration, start.elapsed());
+
+        generation.response(generated_text, timing)
+    }
+
+    // Sample n completions of one prompt, the prompt is encoded and processed once
+    // Each completion gets its own seed and is retired independently as it finishes
+    fn generate_text_n(&self, request: LlamaGenerateTextRequest, n: usize) -> Result<LlamaGenerateTextResponse, CandleError> {
+        self.require_full_precision("n > 1")?;
+        println!("Starting the text generation for {} completions...", n);
+        let start = Instant::now();
+        let tokens = self.tokenizer.encode(&request.prompt, true)?;
+        let mut sequences = Vec::with_capacity(n);
+        for index in 0..n {
+            let mut sequence = LlamaSequence::from_tokens(self, &request, tokens.clone())?;
+            sequence.offset_seed(index as u64);
+            sequences.push((sequence, index));
+        }
+
+        let mut batch = LlamaBatch::new(self);
+        let mut finished = batch.prefill_shared(sequences)?;
+        while !batch.is_empty() {
+            finished.extend(batch.step()?);
+        }
+
+        let mut responses = Vec::with_capacity(n);
+        for entry in finished {
+            let (index, response) = entry.finish();
+            responses.push((index, response?));
+        }
+        responses.sort_by_key(|(index, _)| *index);
+
+        // Every completion shares the prompt pass, so the first one's prompt fields hold for all
+        let (prompt, prompt_tokens, prompt_duration, context) = match responses.first_mut() {
+            Some((_, response)) => (
+                response.prompt.take(),
+                response.usage.prompt_tokens,
+                Duration::from_millis(response.timing.prompt_duration_ms),
+                response.context.take(),
+            ),
+            None => return Err(CandleError::InvalidParameterError("No completion was generated".into())),
+        };
+        let completion_tokens = responses.iter().map(|(_, response)| response.usage.completion_tokens).sum();
+        let choices = responses.into_iter()
+            .map(|(index, response)| LlamaChoice::new(index, response))
+            .collect::<Vec<LlamaChoice>>();
+        let first = choices[0].clone();
+
+        Ok(LlamaGenerateTextResponse {
+            generated_text: first.text,
+            prompt,
+            finish_reason: first.finish_reason,
+            usage: LlamaUsage::new(prompt_tokens, completion_tokens),
+            timing: LlamaTiming::new(Some(prompt_duration), start.elapsed()),
+            logprobs: first.logprobs,
+            beams: None,
+            choices: Some(choices),
+            context,
+        })
+    }
+
+    // Decode several prompts together, left-padded into one batch with a single forward pass per step
+    // Sequences are retired from the batch independently as they finish
+    pub async fn generate_text_batch(&self, requests: Vec<LlamaGenerateTextRequest>) -> Result<Vec<LlamaGenerateTextResponse>, CandleError> {
+        self.require_full_precision("Batched generation")?;
+        println!("Starting the batched text generation for {} prompts...", requests.len());
+        let sequences = requests.iter()
+            .enumerate()
+            .map(|(index, request)| Ok((LlamaSequence::new(self, request)?, index)))
+            .collect::<Result<Vec<(LlamaSequence, usize)>, CandleError>>()?;
+
+        // Rows end on the longest prompt of their batch, so prompts that would run a shorter one past
+        // the context window are decoded in a later batch
+        let mut batch = LlamaBatch::new(self);
+        let max_position = batch.max_position()?;
+        let mut groups: Vec<Vec<(LlamaSequence, usize)>> = Vec::new();
+        for (sequence, index) in sequences {
+            match groups.last_mut() {
+                Some(group) if fits_prefill(group.iter().map(|(sequence, _)| sequence).chain([&sequence]), max_position) => {
+                    group.push((sequence, index));
                 }
+                _ => groups.push(vec![(sequence, index)]),
+            }
+        }
+
+        let mut finished = Vec::new();
+        for group in groups {
+            finished.extend(batch.prefill(group)?);
+            while !batch.is_empty() {
+                finished.extend(batch.step()?);
             }
         }
 
-        // Decode the tokens into a string
-        let generated_text = tokens.iter()
-            .map(|&id| self.tokenizer.decode(&[id], false).unwrap_or_default())
-            .collect::<String>();
+        let mut responses = finished.into_iter()
+            .map(|entry| entry.finish())
+            .collect::<Vec<(usize, Result<LlamaGenerateTextResponse, CandleError>)>>();
+        responses.sort_by_key(|(index, _)| *index);
+
+        responses.into_iter().map(|(_, response)| response).collect()
+    }
+
+    // Render the conversation with the chat template and generate the assistant's reply
+    pub async fn chat(&self, request: LlamaChatRequest) -> Result<LlamaGenerateTextResponse, CandleError> {
+        let request = self.chat_request(request)?;
+
+        self.generate_text(request).await
+    }
+
+    pub fn chat_stream(self: &Arc<Self>, request: LlamaChatRequest) -> Result<impl Stream<Item = Result<LlamaStreamEvent, CandleError>>, CandleError> {
+        let request = self.chat_request(request)?;
+
+        Ok(self.generate_text_stream(request))
+    }
+
+    fn chat_request(&self, request: LlamaChatRequest) -> Result<LlamaGenerateTextRequest, CandleError> {
+        let prompt = self.tokenizer.apply_chat_template(&request.messages, true)?;
+
+        Ok(request.into_generate_request(prompt))
+    }
+
+    // Stream decoded text chunks as they are generated, ending with a Done event
+    // Generation runs on a blocking thread and hands its events over a bounded channel, so a slow
+    // consumer pauses generation and a dropped stream stops it
+    pub fn generate_text_stream(self: &Arc<Self>, request: LlamaGenerateTextRequest) -> impl Stream<Item = Result<LlamaStreamEvent, CandleError>> {
+        println!("Starting the streaming text generation...");
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER_SIZE);
+        let model = self.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = model.send_stream_events(&request, &sender) {
+                let _ = sender.blocking_send(Err(err));
+            }
+        });
+
+        stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|event| (event, receiver))
+        })
+    }
+
+    // Generate into the channel, returning early once the receiving stream has been dropped
+    fn send_stream_events(
+        &self,
+        request: &LlamaGenerateTextRequest,
+        sender: &mpsc::Sender<Result<LlamaStreamEvent, CandleError>>,
+    ) -> Result<(), CandleError> {
+        let mut generation = LlamaGeneration::new(self, request)?;
+
+        while let Some(token) = generation.next_token()? {
+            if let Some(text) = generation.decode_next(token)? {
+                if sender.blocking_send(Ok(LlamaStreamEvent::Token { text })).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Some(text) = generation.decode_rest()? {
+            if sender.blocking_send(Ok(LlamaStreamEvent::Token { text })).is_err() {
+                return Ok(());
+            }
+        }
+
+        let done = LlamaStreamEvent::Done {
+            finish_reason: generation.finish_reason().unwrap_or(LlamaFinishReason::Length),
+            prompt_tokens: generation.prompt_tokens(),
+            completion_tokens: generation.completion_tokens(),
+            context: generation.context().cloned(),
+        };
+        let _ = sender.blocking_send(Ok(done));
+
+        Ok(())
+    }
+
+    // Embed the inputs from the final hidden layer in batches of at most embedding_batch_size,
+    // each right-padded to its longest input
+    // The causal mask already keeps real tokens from attending to the padding that follows them
+    pub async fn embed(&self, request: LlamaEmbeddingRequest) -> Result<LlamaEmbeddingResponse, CandleError> {
+        self.require_full_precision("Embeddings")?;
+
+        // Ensure model is initialized
+        let model = self.model.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let llama_config = self.llama_config.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let device = self.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let dtype = self.dtype.ok_or(CandleError::UninitializedModelError)?;
+
+        if request.inputs.is_empty() {
+            return Err(CandleError::InvalidParameterError("inputs must not be empty".into()));
+        }
+        // Under causal attention the first position only ever sees BOS, so it carries no information
+        if request.pooling == Pooling::Cls {
+            return Err(CandleError::InvalidParameterError("cls pooling is not supported by causal models, use mean or last_token".into()));
+        }
+        if self.config.embedding_batch_size == 0 {
+            return Err(CandleError::InvalidParameterError("embedding_batch_size must be > 0".into()));
+        }
+
+        // Inputs longer than the context window follow the truncation policy
+        let truncation = request.truncation.unwrap_or(self.config.truncation);
+        let context_length = self.context_length()?;
+        let mut encodings = Vec::with_capacity(request.inputs.len());
+        for input in &request.inputs {
+            let tokens = self.tokenizer.encode(input, true)?;
+            encodings.push(fit_context(tokens, 0, context_length, truncation)?.tokens);
+        }
+
+        let mut embeddings = Vec::with_capacity(encodings.len());
+        for encodings in encodings.chunks(self.config.embedding_batch_size) {
+            let max_len = encodings.iter().map(|tokens| tokens.len()).max().unwrap_or(0);
+            let mut input = Vec::with_capacity(encodings.len() * max_len);
+            let mut mask = Vec::with_capacity(encodings.len() * max_len);
+            for tokens in encodings {
+                let pad_len = max_len - tokens.len();
+                input.extend_from_slice(tokens);
+                input.extend(std::iter::repeat(PAD_TOKEN_ID).take(pad_len));
+                mask.extend(std::iter::repeat(1f32).take(tokens.len()));
+                mask.extend(std::iter::repeat(0f32).take(pad_len));
+            }
+            let input = Tensor::from_vec(input, (encodings.len(), max_len), device)?;
+            let mask = Tensor::from_vec(mask, (encodings.len(), max_len), device)?;
+
+            let mut cache = LlamaCache::new(false, dtype, llama_config, device)?;
+            let hidden = model.forward_hidden(&input, 0, &mut cache, None)?;
+            let mut pooled = pool(&hidden, &mask, request.pooling)?;
+            if request.normalize {
+                pooled = normalize_l2(&pooled)?;
+            }
+            embeddings.extend(pooled.to_vec2::<f32>()?);
+        }
+
+        let prompt_tokens = encodings.iter().map(|tokens| tokens.len()).sum();
+
+        Ok(LlamaEmbeddingResponse {
+            embeddings,
+            usage: LlamaUsage::new(prompt_tokens, 0),
+        })
+    }
+
+    // Score the prompt, or the continuation given the prompt, returning per-token logprobs and perplexity
+    pub async fn score_text(&self, request: LlamaScoreRequest) -> Result<LlamaScoreResponse, CandleError> {
+        self.require_full_precision("Scoring")?;
+        // Ensure model is initialized
+        let model = self.model.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let llama_config = self.llama_config.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let device = self.device.as_ref().ok_or(CandleError::UninitializedModelError)?;
+        let dtype = self.dtype.ok_or(CandleError::UninitializedModelError)?;
+
+        let mut tokens = self.tokenizer.encode(&request.prompt, true)?;
+        let prompt_tokens = tokens.len();
+
+        // The first token has nothing to condition on, so scoring starts after it
+        let start_at = match &request.continuation {
+            Some(continuation) => {
+                tokens.extend(self.tokenizer.encode(continuation, false)?);
+                prompt_tokens.max(1)
+            }
+            None => 1,
+        };
+        if start_at >= tokens.len() {
+            return Err(CandleError::InvalidParameterError("Nothing to score, text is too short".into()));
+        }
+        let context_length = self.context_length()?;
+        if tokens.len() > context_length {
+            return Err(CandleError::InvalidParameterError(format!("Text has {} tokens but the context window is {}", tokens.len(), context_length)));
+        }
+
+        // One pass over the whole text, the hidden state at each position predicts the next token
+        let mut cache = LlamaCache::new(false, dtype, llama_config, device)?;
+        let input = Tensor::new(tokens.as_slice(), device)?.unsqueeze(0)?;
+        let hidden = model.forward_hidden(&input, 0, &mut cache, None)?;
+        let hidden = hidden.narrow(1, start_at - 1, tokens.len() - start_at)?.squeeze(0)?.contiguous()?;
+
+        // Pick each target token's logprob on the device so only one value per position is copied back
+        let targets = Tensor::new(&tokens[start_at..], device)?;
+        let logprobs = log_softmax(&model.logits(&hidden)?, D::Minus1)?
+            .gather(&targets.unsqueeze(1)?, 1)?
+            .squeeze(1)?
+            .to_vec1::<f32>()?;
+
+        let mut scores = Vec::with_capacity(tokens.len() - start_at);
+        for (&token, logprob) in tokens[start_at..].iter().zip(logprobs) {
+            scores.push(LlamaTokenScore {
+                token_id: token,
+                text: self.tokenizer.decode(&[token], false)?,
+                logprob,
+            });
+        }
+
+        let total_logprob = scores.iter().map(|score| score.logprob).sum::<f32>();
+        let perplexity = (-total_logprob / scores.len() as f32).exp();
 
-        Ok(LlamaGenerateTextResponse { generated_text })
+        Ok(LlamaScoreResponse {
+            usage: LlamaUsage::new(prompt_tokens, tokens.len() - prompt_tokens),
+            tokens: scores,
+            total_logprob,
+            perplexity,
+        })
     }
 }
\ No newline at end of file
@@ -0,0 +1,41 @@
+// src/gateway/clients/candle/llama/quantized.rs
+
+/// Candle API Llama Quantized
+/// Loads quantized GGUF and GGML Llama weights (Q4_K, Q5_K, Q8_0, ...) for CPU-friendly inference.
+
+// Core Crates
+use std::fs::File;
+use std::path::Path;
+
+// Candle Crates
+use candle_core::Device;
+use candle_core::quantized::{ggml_file, gguf_file};
+use candle_transformers::models::quantized_llama::{ModelWeights, MAX_SEQ_LEN};
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+
+// GGUF files describe their own hyper-parameters, legacy GGML files need the grouped-query attention factor
+// Returns the weights with their context length, read from the GGUF metadata when present
+// The quantized model only builds rope tables for MAX_SEQ_LEN positions, so the context length never exceeds it
+pub fn load_quantized_weights(path: &Path, gqa: usize, device: &Device) -> Result<(ModelWeights, usize), CandleError> {
+    let mut file = File::open(path)
+        .map_err(|_| CandleError::LoadModelError(format!("Failed to open {}", path.display())))?;
+
+    let is_gguf = path.extension().and_then(|extension| extension.to_str()) == Some("gguf");
+    if is_gguf {
+        let content = gguf_file::Content::read(&mut file).map_err(|err| err.with_path(path))?;
+        println!("Loaded {} quantized tensors from GGUF", content.tensor_infos.len());
+        let context_length = match content.metadata.get("llama.context_length") {
+            Some(value) => (value.to_u32()? as usize).min(MAX_SEQ_LEN),
+            None => MAX_SEQ_LEN,
+        };
+
+        Ok((ModelWeights::from_gguf(content, &mut file, device)?, context_length))
+    } else {
+        let content = ggml_file::Content::read(&mut file, device).map_err(|err| err.with_path(path))?;
+        println!("Loaded {} quantized tensors from GGML", content.tensors.len());
+
+        Ok((ModelWeights::from_ggml(content, gqa)?, MAX_SEQ_LEN))
+    }
+}
\ No newline at end of file
@@ -0,0 +1,280 @@
+// src/gateway/clients/candle/llama/sampling.rs
+
+/// Candle API Llama Sampling
+/// Per-request sampling parameters that override the model's configured defaults,
+/// and the logits pipeline built from them.
+
+// Core Crates
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::logits::{
+    FrequencyPresencePenalty, LogitBias, LogitsPipeline, MinP, RepeatPenalty, Temperature, TopK, TopP, TypicalP,
+};
+use crate::gateway::clients::candle::llama::config::LlamaModelConfig;
+
+// Every field is optional, unset fields fall back to the LlamaModelConfig defaults
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SamplingParams {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub seed: Option<u64>,
+    pub sample_len: Option<usize>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<usize>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    // Always pick the most likely token, ignoring the other sampling settings
+    pub greedy: Option<bool>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    // Bias added to the logits of specific token ids
+    #[serde(default)]
+    pub logit_bias: HashMap<u32, f32>,
+}
+
+// Sampling parameters after merging with the model defaults and validating
+#[derive(Debug, Clone)]
+pub struct ResolvedSamplingParams {
+    pub temperature: f64,
+    pub top_p: Option<f64>,
+    pub seed: u64,
+    pub sample_len: usize,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub typical_p: Option<f64>,
+    pub greedy: bool,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    pub logit_bias: HashMap<u32, f32>,
+}
+
+impl SamplingParams {
+    pub fn resolve(&self, defaults: &LlamaModelConfig) -> Result<ResolvedSamplingParams, CandleError> {
+        let params = ResolvedSamplingParams {
+            temperature: self.temperature.or(defaults.temperature).unwrap_or(1.0),
+            top_p: self.top_p.or(defaults.top_p),
+            seed: self.seed.unwrap_or(defaults.seed),
+            sample_len: self.sample_len.unwrap_or(defaults.sample_len),
+            repeat_penalty: self.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.unwrap_or(defaults.repeat_last_n),
+            top_k: self.top_k,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            greedy: self.greedy.unwrap_or(false),
+            frequency_penalty: self.frequency_penalty.unwrap_or(0.0),
+            presence_penalty: self.presence_penalty.unwrap_or(0.0),
+            logit_bias: self.logit_bias.clone(),
+        };
+
+        params.validate()?;
+
+        Ok(params)
+    }
+}
+
+impl ResolvedSamplingParams {
+    pub fn validate(&self) -> Result<(), CandleError> {
+        if !self.temperature.is_finite() || self.temperature < 0.0 {
+            return Err(CandleError::InvalidParameterError(format!("temperature must be >= 0, got {}", self.temperature)));
+        }
+
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(CandleError::InvalidParameterError(format!("top_p must be in (0, 1], got {}", top_p)));
+            }
+        }
+
+        if self.top_k == Some(0) {
+            return Err(CandleError::InvalidParameterError("top_k must be > 0".into()));
+        }
+
+        if let Some(min_p) = self.min_p {
+            if !(0.0..=1.0).contains(&min_p) {
+                return Err(CandleError::InvalidParameterError(format!("min_p must be in [0, 1], got {}", min_p)));
+            }
+        }
+
+        if let Some(typical_p) = self.typical_p {
+            if !(typical_p > 0.0 && typical_p <= 1.0) {
+                return Err(CandleError::InvalidParameterError(format!("typical_p must be in (0, 1], got {}", typical_p)));
+            }
+        }
+
+        if self.sample_len == 0 {
+            return Err(CandleError::InvalidParameterError("sample_len must be > 0".into()));
+        }
+
+        if !self.repeat_penalty.is_finite() || self.repeat_penalty <= 0.0 {
+            return Err(CandleError::InvalidParameterError(format!("repeat_penalty must be > 0, got {}", self.repeat_penalty)));
+        }
+
+        if !(-2.0..=2.0).contains(&self.frequency_penalty) {
+            return Err(CandleError::InvalidParameterError(format!("frequency_penalty must be in [-2, 2], got {}", self.frequency_penalty)));
+        }
+
+        if !(-2.0..=2.0).contains(&self.presence_penalty) {
+            return Err(CandleError::InvalidParameterError(format!("presence_penalty must be in [-2, 2], got {}", self.presence_penalty)));
+        }
+
+        if let Some((token, bias)) = self.logit_bias.iter().find(|(_, bias)| !(-100.0..=100.0).contains(*bias)) {
+            return Err(CandleError::InvalidParameterError(format!("logit_bias for token {} must be in [-100, 100], got {}", token, bias)));
+        }
+
+        Ok(())
+    }
+
+    // Build the logits pipeline, a zero temperature behaves like greedy decoding
+    // The context passed to the pipeline starts with the prompt_tokens of the prompt
+    pub fn logits_pipeline(&self, prompt_tokens: usize) -> LogitsPipeline {
+        let pipeline = if self.greedy || self.temperature < 1e-7 {
+            LogitsPipeline::greedy()
+        } else {
+            LogitsPipeline::multinomial(self.seed)
+        };
+
+        let mut pipeline = if self.repeat_penalty != 1.0 {
+            pipeline.with_stage(RepeatPenalty { penalty: self.repeat_penalty, last_n: self.repeat_last_n })
+        } else {
+            pipeline
+        };
+        if self.frequency_penalty != 0.0 || self.presence_penalty != 0.0 {
+            pipeline = pipeline.with_stage(FrequencyPresencePenalty {
+                frequency_penalty: self.frequency_penalty,
+                presence_penalty: self.presence_penalty,
+                prompt_tokens,
+            });
+        }
+        if !self.logit_bias.is_empty() {
+            pipeline = pipeline.with_stage(LogitBias(self.logit_bias.clone()));
+        }
+
+        // Greedy decoding only needs the penalties and biases, filtering can't change the argmax
+        if self.greedy || self.temperature < 1e-7 {
+            return pipeline;
+        }
+
+        pipeline = pipeline.with_stage(Temperature(self.temperature as f32));
+        if let Some(top_k) = self.top_k {
+            pipeline = pipeline.with_stage(TopK(top_k));
+        }
+        if let Some(top_p) = self.top_p.filter(|&top_p| top_p < 1.0) {
+            pipeline = pipeline.with_stage(TopP(top_p as f32));
+        }
+        if let Some(min_p) = self.min_p.filter(|&min_p| min_p > 0.0) {
+            pipeline = pipeline.with_stage(MinP(min_p as f32));
+        }
+        if let Some(typical_p) = self.typical_p.filter(|&typical_p| typical_p < 1.0) {
+            pipeline = pipeline.with_stage(TypicalP(typical_p as f32));
+        }
+
+        pipeline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(params: SamplingParams) -> Result<ResolvedSamplingParams, CandleError> {
+        params.resolve(&LlamaModelConfig::default())
+    }
+
+    #[test]
+    fn test_unset_params_fall_back_to_model_defaults() {
+        let defaults = LlamaModelConfig {
+            temperature: Some(0.7),
+            top_p: Some(0.5),
+            seed: 7,
+            sample_len: 20,
+            repeat_penalty: 1.1,
+            repeat_last_n: 32,
+            ..Default::default()
+        };
+        let params = SamplingParams::default().resolve(&defaults).unwrap();
+        assert_eq!(params.temperature, 0.7);
+        assert_eq!(params.top_p, Some(0.5));
+        assert_eq!(params.seed, 7);
+        assert_eq!(params.sample_len, 20);
+        assert_eq!(params.repeat_penalty, 1.1);
+        assert_eq!(params.repeat_last_n, 32);
+
+        // Without a configured temperature sampling runs at 1.0
+        let defaults = LlamaModelConfig { temperature: None, ..Default::default() };
+        assert_eq!(SamplingParams::default().resolve(&defaults).unwrap().temperature, 1.0);
+    }
+
+    #[test]
+    fn test_request_params_override_model_defaults() {
+        let params = resolve(SamplingParams {
+            temperature: Some(0.2),
+            top_p: Some(0.95),
+            seed: Some(1),
+            sample_len: Some(5),
+            repeat_penalty: Some(1.3),
+            repeat_last_n: Some(8),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(params.temperature, 0.2);
+        assert_eq!(params.top_p, Some(0.95));
+        assert_eq!(params.seed, 1);
+        assert_eq!(params.sample_len, 5);
+        assert_eq!(params.repeat_penalty, 1.3);
+        assert_eq!(params.repeat_last_n, 8);
+    }
+
+    #[test]
+    fn test_validate_temperature_and_top_p_bounds() {
+        assert!(resolve(SamplingParams { temperature: Some(0.0), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { temperature: Some(-0.1), ..Default::default() }).is_err());
+        assert!(resolve(SamplingParams { temperature: Some(f64::NAN), ..Default::default() }).is_err());
+        assert!(resolve(SamplingParams { temperature: Some(f64::INFINITY), ..Default::default() }).is_err());
+
+        assert!(resolve(SamplingParams { top_p: Some(1.0), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { top_p: Some(1e-6), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { top_p: Some(0.0), ..Default::default() }).is_err());
+        assert!(resolve(SamplingParams { top_p: Some(1.01), ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_validate_sample_len_and_repeat_penalty_bounds() {
+        assert!(resolve(SamplingParams { sample_len: Some(1), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { sample_len: Some(0), ..Default::default() }).is_err());
+
+        assert!(resolve(SamplingParams { repeat_penalty: Some(1e-3), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { repeat_penalty: Some(0.0), ..Default::default() }).is_err());
+        assert!(resolve(SamplingParams { repeat_penalty: Some(f32::NAN), ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_validate_filter_bounds() {
+        assert!(resolve(SamplingParams { top_k: Some(1), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { top_k: Some(0), ..Default::default() }).is_err());
+
+        assert!(resolve(SamplingParams { min_p: Some(0.0), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { min_p: Some(1.0), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { min_p: Some(-0.01), ..Default::default() }).is_err());
+        assert!(resolve(SamplingParams { min_p: Some(1.01), ..Default::default() }).is_err());
+
+        assert!(resolve(SamplingParams { typical_p: Some(1.0), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { typical_p: Some(0.0), ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_validate_penalty_and_bias_bounds() {
+        assert!(resolve(SamplingParams { frequency_penalty: Some(-2.0), presence_penalty: Some(2.0), ..Default::default() }).is_ok());
+        assert!(resolve(SamplingParams { frequency_penalty: Some(2.01), ..Default::default() }).is_err());
+        assert!(resolve(SamplingParams { presence_penalty: Some(-2.01), ..Default::default() }).is_err());
+
+        let logit_bias = HashMap::from([(1, 100.0), (2, -100.0)]);
+        assert!(resolve(SamplingParams { logit_bias, ..Default::default() }).is_ok());
+        let logit_bias = HashMap::from([(1, 100.5)]);
+        assert!(resolve(SamplingParams { logit_bias, ..Default::default() }).is_err());
+    }
+}
\ No newline at end of file
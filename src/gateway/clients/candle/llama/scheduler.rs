@@ -0,0 +1,348 @@
+// src/gateway/clients/candle/llama/scheduler.rs
+
+/// Candle API Llama Scheduler
+/// Continuous batching: requests are queued asynchronously and admitted into the running batch
+/// between decode steps, each result is returned through its own channel.
+
+// Core Crates
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::llama::batch::{fits_prefill, LlamaBatch, LlamaBatchEntry, LlamaBatchSlot};
+use crate::gateway::clients::candle::llama::generator::{LlamaGenerateTextRequest, LlamaGenerateTextResponse, LlamaSequence};
+use crate::gateway::clients::candle::llama::model::LlamaModel;
+use crate::northbound_bus::{send_telemetry, TelemetryData};
+
+type LlamaResponder = oneshot::Sender<Result<LlamaGenerateTextResponse, CandleError>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LlamaSchedulerConfig {
+    // Most sequences decoded together in one step
+    pub max_batch_size: usize,
+    // Most tokens the running batch may hold, each sequence reserving its prompt plus sample_len
+    pub max_total_tokens: usize,
+    // Most requests waiting for admission, further requests are rejected until the queue drains
+    pub max_queue_depth: usize,
+}
+
+impl Default for LlamaSchedulerConfig {
+    fn default() -> Self {
+        LlamaSchedulerConfig {
+            max_batch_size: 8,
+            max_total_tokens: 16384,
+            max_queue_depth: 64,
+        }
+    }
+}
+
+impl LlamaSchedulerConfig {
+    // Whether a sequence fits the limits at all, running alone in an empty batch
+    fn accepts(&self, sequence: &impl LlamaBatchSlot) -> bool {
+        sequence.reserved_tokens() <= self.max_total_tokens
+    }
+
+    // Whether a batch of batch_len sequences reserving reserved_tokens has room for one more
+    fn has_room(&self, batch_len: usize, reserved_tokens: usize, sequence: &impl LlamaBatchSlot) -> bool {
+        batch_len < self.max_batch_size && reserved_tokens + sequence.reserved_tokens() <= self.max_total_tokens
+    }
+}
+
+struct LlamaScheduledRequest {
+    request: LlamaGenerateTextRequest,
+    responder: LlamaResponder,
+}
+
+pub struct LlamaScheduler {
+    sender: mpsc::UnboundedSender<LlamaScheduledRequest>,
+    queue_depth: Arc<AtomicUsize>,
+    max_queue_depth: usize,
+}
+
+impl LlamaScheduler {
+    // Start the decode loop on a blocking thread, it stops once the scheduler is dropped
+    pub fn new(model: Arc<LlamaModel>, config: LlamaSchedulerConfig) -> Result<Self, CandleError> {
+        model.require_full_precision("Continuous batching")?;
+        if config.max_batch_size == 0 {
+            return Err(CandleError::InvalidParameterError("max_batch_size must be > 0".into()));
+        }
+        if config.max_total_tokens == 0 {
+            return Err(CandleError::InvalidParameterError("max_total_tokens must be > 0".into()));
+        }
+        if config.max_queue_depth == 0 {
+            return Err(CandleError::InvalidParameterError("max_queue_depth must be > 0".into()));
+        }
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queue_depth = Arc::new(AtomicUsize::new(0));
+        let loop_queue_depth = queue_depth.clone();
+        let max_queue_depth = config.max_queue_depth;
+        tokio::task::spawn_blocking(move || run_decode_loop(&model, &config, receiver, &loop_queue_depth));
+
+        Ok(LlamaScheduler { sender, queue_depth, max_queue_depth })
+    }
+
+    // Queue a request, the receiver resolves once its sequence has finished
+    // A full queue rejects the request straight away instead of letting it grow without bound
+    pub fn submit(&self, request: LlamaGenerateTextRequest) -> oneshot::Receiver<Result<LlamaGenerateTextResponse, CandleError>> {
+        let (responder, receiver) = oneshot::channel();
+        let reserved = self.queue_depth.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |depth| {
+            (depth < self.max_queue_depth).then_some(depth + 1)
+        });
+        if reserved.is_err() {
+            let _ = responder.send(Err(CandleError::SchedulerError(format!(
+                "Queue is full with {} waiting requests",
+                self.max_queue_depth
+            ))));
+            return receiver;
+        }
+
+        if let Err(mpsc::error::SendError(scheduled)) = self.sender.send(LlamaScheduledRequest { request, responder }) {
+            self.queue_depth.fetch_sub(1, Ordering::SeqCst);
+            let _ = scheduled.responder.send(Err(CandleError::SchedulerError("Scheduler has stopped".into())));
+        }
+
+        receiver
+    }
+
+    pub async fn generate_text(&self, request: LlamaGenerateTextRequest) -> Result<LlamaGenerateTextResponse, CandleError> {
+        let response = match self.submit(request).await {
+            Ok(response) => response,
+            Err(_) => Err(CandleError::SchedulerError("Scheduler dropped the request".into())),
+        };
+
+        // Report the outcome with the current queue depth on the northbound bus
+        let telemetry = TelemetryData {
+            request_summary: "Llama scheduled generation".into(),
+            response_summary: match &response {
+                Ok(response) => format!("{:?} after {} tokens", response.finish_reason, response.usage.completion_tokens),
+                Err(_) => "Failed".into(),
+            },
+            error_info: response.as_ref().err().map(|err| err.to_string()),
+            queue_depth: Some(self.queue_depth()),
+        };
+        if let Err(err) = send_telemetry(&telemetry).await {
+            println!("Failed to send scheduler telemetry: {}", err);
+        }
+
+        response
+    }
+
+    // Requests waiting to be admitted into the running batch
+    pub fn queue_depth(&self) -> usize {
+        self.queue_depth.load(Ordering::SeqCst)
+    }
+}
+
+fn run_decode_loop(
+    model: &LlamaModel,
+    config: &LlamaSchedulerConfig,
+    mut receiver: mpsc::UnboundedReceiver<LlamaScheduledRequest>,
+    queue_depth: &AtomicUsize,
+) {
+    let mut batch = LlamaBatch::new(model);
+    let mut pending = VecDeque::new();
+    let mut open = true;
+
+    while open || !pending.is_empty() || !batch.is_empty() {
+        // Sleep until a request arrives when there is nothing to decode
+        if batch.is_empty() && pending.is_empty() {
+            match receiver.blocking_recv() {
+                Some(scheduled) => enqueue(model, config, scheduled, &mut pending, queue_depth),
+                None => break,
+            }
+        }
+        loop {
+            match receiver.try_recv() {
+                Ok(scheduled) => enqueue(model, config, scheduled, &mut pending, queue_depth),
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => {
+                    open = false;
+                    break;
+                }
+            }
+        }
+
+        let mut finished = Vec::new();
+        let result = admit(&mut batch, config, &mut pending, queue_depth, &mut finished)
+            .map_err(|err| format!("Prefill failed: {}", err))
+            .and_then(|_| {
+                batch.step()
+                    .map(|entries| finished.extend(entries))
+                    .map_err(|err| format!("Decode step failed: {}", err))
+            });
+
+        // Sequences that finished before a failure still get their response
+        for entry in finished {
+            respond(entry);
+        }
+
+        if let Err(message) = result {
+            // A failed prefill or step leaves the cache in an unknown state, so every sequence in the batch fails
+            println!("Llama scheduler: {}", message);
+            for entry in batch.clear() {
+                let _ = entry.tag.send(Err(CandleError::SchedulerError(message.clone())));
+            }
+        }
+    }
+}
+
+// Validate and tokenize a request as it arrives, rejecting it straight away when invalid
+// A request that could not run even alone in an empty batch would block the queue, so it is rejected too
+fn enqueue<'a>(
+    model: &'a LlamaModel,
+    config: &LlamaSchedulerConfig,
+    scheduled: LlamaScheduledRequest,
+    pending: &mut VecDeque<(LlamaSequence<'a>, LlamaResponder)>,
+    queue_depth: &AtomicUsize,
+) {
+    let sequence = LlamaSequence::new(model, &scheduled.request).and_then(|sequence| {
+        if !config.accepts(&sequence) {
+            return Err(CandleError::SchedulerError(format!(
+                "Request reserves {} tokens but max_total_tokens is {}",
+                sequence.reserved_tokens(),
+                config.max_total_tokens
+            )));
+        }
+        if !fits_prefill([&sequence], model.context_length()?) {
+            return Err(CandleError::SchedulerError("Request does not fit the context window".into()));
+        }
+
+        Ok(sequence)
+    });
+
+    match sequence {
+        Ok(sequence) => pending.push_back((sequence, scheduled.responder)),
+        Err(err) => {
+            queue_depth.fetch_sub(1, Ordering::SeqCst);
+            let _ = scheduled.responder.send(Err(err));
+        }
+    }
+}
+
+// Admit queued sequences in arrival order while the batch has room for them
+// A sequence that does not fit yet blocks the ones behind it so it is not starved
+// Sequences that finish on their first token are added to finished
+// Admitted sequences are never dropped on failure, a failed prefill leaves them in the batch and a
+// failed join hands the responder back, so every request still gets a response
+fn admit<'a>(
+    batch: &mut LlamaBatch<'a, LlamaResponder>,
+    config: &LlamaSchedulerConfig,
+    pending: &mut VecDeque<(LlamaSequence<'a>, LlamaResponder)>,
+    queue_depth: &AtomicUsize,
+    finished: &mut Vec<LlamaBatchEntry<'a, LlamaResponder>>,
+) -> Result<(), CandleError> {
+    let max_position = batch.max_position()?;
+
+    // An empty batch starts over, prefilling the head of the queue together
+    if batch.is_empty() {
+        let count = prefill_count(pending.iter().map(|(sequence, _)| sequence), config, max_position);
+        let sequences = pending.drain(..count).collect::<Vec<(LlamaSequence, LlamaResponder)>>();
+        queue_depth.fetch_sub(sequences.len(), Ordering::SeqCst);
+        finished.extend(batch.prefill(sequences)?);
+
+        return Ok(());
+    }
+
+    while let Some((sequence, _)) = pending.front() {
+        if !config.has_room(batch.len(), batch.reserved_tokens(), sequence) {
+            break;
+        }
+        match batch.can_join(sequence) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                if let Some((_, responder)) = pending.pop_front() {
+                    queue_depth.fetch_sub(1, Ordering::SeqCst);
+                    let _ = responder.send(Err(err));
+                }
+                continue;
+            }
+        }
+
+        if let Some((sequence, responder)) = pending.pop_front() {
+            queue_depth.fetch_sub(1, Ordering::SeqCst);
+
+            // A failed join only concerns the joining sequence, the running batch carries on
+            match batch.join(sequence, responder) {
+                Ok(entry) => finished.extend(entry),
+                Err((responder, err)) => {
+                    let _ = responder.send(Err(err));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Number of sequences from the head of the queue that start an empty batch together
+fn prefill_count<'s, S: LlamaBatchSlot + 's>(
+    queue: impl IntoIterator<Item = &'s S>,
+    config: &LlamaSchedulerConfig,
+    max_position: usize,
+) -> usize {
+    let mut admitted = Vec::new();
+    let mut reserved_tokens = 0;
+    for sequence in queue {
+        let fits = config.has_room(admitted.len(), reserved_tokens, sequence)
+            && fits_prefill(admitted.iter().copied().chain([sequence]), max_position);
+        if !fits {
+            break;
+        }
+
+        reserved_tokens += sequence.reserved_tokens();
+        admitted.push(sequence);
+    }
+
+    admitted.len()
+}
+
+fn respond(entry: LlamaBatchEntry<LlamaResponder>) {
+    let (responder, response) = entry.finish();
+    let _ = responder.send(response);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gateway::clients::candle::llama::batch::tests::slot;
+
+    #[test]
+    fn test_oversized_request_is_not_accepted() {
+        let config = LlamaSchedulerConfig { max_batch_size: 4, max_total_tokens: 100, ..Default::default() };
+        assert!(config.accepts(&slot(50, 50)));
+        assert!(!config.accepts(&slot(50, 51)));
+    }
+
+    #[test]
+    fn test_room_is_limited_by_batch_size_and_tokens() {
+        let config = LlamaSchedulerConfig { max_batch_size: 2, max_total_tokens: 100, ..Default::default() };
+        assert!(config.has_room(1, 60, &slot(20, 20)));
+        assert!(!config.has_room(2, 0, &slot(1, 1)));
+        assert!(!config.has_room(1, 60, &slot(20, 21)));
+    }
+
+    #[test]
+    fn test_prefill_stops_at_the_first_sequence_that_does_not_fit() {
+        let config = LlamaSchedulerConfig { max_batch_size: 3, max_total_tokens: 1000, ..Default::default() };
+        let queue = [slot(10, 10), slot(10, 10), slot(10, 10), slot(10, 10)];
+        assert_eq!(prefill_count(&queue, &config, 100), 3);
+
+        // The third would reach the token limit, the fourth must wait behind it
+        let config = LlamaSchedulerConfig { max_batch_size: 8, max_total_tokens: 50, ..Default::default() };
+        let queue = [slot(10, 10), slot(10, 10), slot(5, 10), slot(1, 1)];
+        assert_eq!(prefill_count(&queue, &config, 100), 2);
+    }
+
+    #[test]
+    fn test_prefill_keeps_short_prompts_within_the_context_window() {
+        let config = LlamaSchedulerConfig::default();
+        let queue = [slot(90, 10), slot(10, 80), slot(80, 10)];
+        assert_eq!(prefill_count(&queue, &config, 100), 1);
+    }
+}
\ No newline at end of file
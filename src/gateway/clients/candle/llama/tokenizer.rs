@@ -1,19 +1,25 @@
 // src/gateway/clients/candle/llama/tokenizer.rs
 
 /// Candle API Llama Tokenizer
-/// Handles downloading and initializing the tokenizer.
+/// Handles downloading and initializing the tokenizer, and incremental detokenization.
+
+// Core Crates
+use std::fs;
 
 // Candle Crates
-use hf_hub::api::tokio::Api;
 use tokenizers::{tokenizer::Tokenizer as HfTokenizer};
 
 // Networking Crates
+use crate::gateway::clients::candle::llama::chat::{LlamaChatMessage, LlamaChatTemplate};
 use crate::gateway::clients::candle::llama::config::LlamaModelConfig;
 use crate::gateway::clients::candle::candle_error::CandleError;
+use crate::gateway::clients::candle::model_source::ModelRepo;
 
 pub struct LlamaTokenizer {
     pub  tokenizer: Option<HfTokenizer>,
     pub model_config: LlamaModelConfig,
+    pub eos_token_ids: Vec<u32>,
+    pub chat_template: Option<LlamaChatTemplate>,
 }
 
 impl LlamaTokenizer {
@@ -21,24 +27,81 @@ impl LlamaTokenizer {
         LlamaTokenizer {
             tokenizer: None,
             model_config,
+            eos_token_ids: Vec::new(),
+            chat_template: None,
         }
     }
 
     pub async fn download_and_load_tokenizer(&mut self) -> Result<(), CandleError> {
-        let api = Api::new().map_err(|_| CandleError::InitializationError("Failed to create API".into()))?;
-        let repo = api.model(self.model_config.model_id.clone().unwrap_or_else(|| "meta-llama/Llama-2-7b-hf".to_string()));
+        let repo = self.model_config.model_repo()?;
 
-        let tokenizer_filename = repo.get("tokenizer.json").await.map_err(|_| CandleError::DownloadError("Failed to get tokenizer".into()))?;
+        let tokenizer_filename = repo.get("tokenizer.json").await?;
 
         let tokenizer = HfTokenizer::from_file(&tokenizer_filename).map_err(|_| CandleError::LoadModelError("Failed to load tokenizer".into()))?;
         println!("Tokenizer loaded for model {}", self.model_config.model_id.as_ref().unwrap_or(&"default-model".to_string()));
 
+        self.eos_token_ids = Self::load_eos_token_ids(&repo, &tokenizer).await;
+        println!("Using end-of-sequence token ids {:?}", self.eos_token_ids);
+
+        self.chat_template = Some(LlamaChatTemplate::load(&repo, &tokenizer).await);
+
         self.tokenizer = Some(tokenizer);
 
         Ok(())
     }
 
-    pub fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, CandleError::SafeTensorError> {
+    // Resolve end-of-sequence ids from config.json and generation_config.json
+    async fn load_eos_token_ids(repo: &ModelRepo, tokenizer: &HfTokenizer) -> Vec<u32> {
+        let mut configs = Vec::new();
+        for filename in ["config.json", "generation_config.json"] {
+            let config = match repo.get(filename).await {
+                Ok(path) => fs::read(path).ok()
+                    .and_then(|config| serde_json::from_slice::<serde_json::Value>(&config).ok()),
+                Err(_) => None,
+            };
+            configs.extend(config);
+        }
+
+        Self::eos_token_ids_from_configs(&configs, tokenizer)
+    }
+
+    // Llama-3 checkpoints list several ids, older checkpoints fall back to </s>
+    fn eos_token_ids_from_configs(configs: &[serde_json::Value], tokenizer: &HfTokenizer) -> Vec<u32> {
+        let mut eos_token_ids = Vec::new();
+
+        for config in configs {
+            match config.get("eos_token_id") {
+                Some(serde_json::Value::Number(id)) => eos_token_ids.extend(id.as_u64().map(|id| id as u32)),
+                Some(serde_json::Value::Array(ids)) => eos_token_ids.extend(ids.iter().filter_map(|id| id.as_u64()).map(|id| id as u32)),
+                _ => {}
+            }
+        }
+
+        if eos_token_ids.is_empty() {
+            eos_token_ids.extend(tokenizer.token_to_id("</s>"));
+        }
+
+        eos_token_ids.sort_unstable();
+        eos_token_ids.dedup();
+        eos_token_ids
+    }
+
+    pub fn eos_token_ids(&self) -> &[u32] {
+        &self.eos_token_ids
+    }
+
+    pub fn is_eos_token(&self, id: u32) -> bool {
+        self.eos_token_ids.contains(&id)
+    }
+
+    // Render chat messages into a prompt with the model's chat template
+    pub fn apply_chat_template(&self, messages: &[LlamaChatMessage], add_generation_prompt: bool) -> Result<String, CandleError> {
+        let chat_template = self.chat_template.as_ref().ok_or_else(|| CandleError::UninitializedModelError("Chat template is not initialized".into()))?;
+
+        chat_template.render(messages, add_generation_prompt)
+    }
+
+    pub fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>, CandleError> {
         let tokenizer = self.tokenizer.as_ref().ok_or_else(|| CandleError::UninitializedModelError("Tokenizer is not initialized".into()))?;
 
         let encodings = tokenizer
@@ -49,7 +112,7 @@ impl LlamaTokenizer {
         Ok(encodings)
     }
 
-    pub fn decode(&self, ids: &[u32], skip_special_tokens: bool) -> Result<String, CandleError::SafeTensorError> {
+    pub fn decode(&self, ids: &[u32], skip_special_tokens: bool) -> Result<String, CandleError> {
         let tokenizer = self.tokenizer.as_ref().ok_or_else(|| CandleError::UninitializedModelError("Tokenizer is not initialized".into()))?;
 
         let decoded = tokenizer
@@ -58,4 +121,180 @@ impl LlamaTokenizer {
 
         Ok(decoded)
     }
+
+    // Start an incremental decoder for freshly generated tokens
+    pub fn output_stream(&self) -> LlamaTokenOutputStream<'_> {
+        LlamaTokenOutputStream {
+            tokenizer: self,
+            tokens: Vec::new(),
+            prev_index: 0,
+            current_index: 0,
+        }
+    }
+
+    // Start an incremental decoder that continues after the given tokens
+    // Keeping the last prefix token in the window preserves the leading space of the next word
+    pub fn output_stream_after(&self, prefix: &[u32]) -> LlamaTokenOutputStream<'_> {
+        let tokens = prefix.last().map(|&id| vec![id]).unwrap_or_default();
+        let index = tokens.len();
+
+        LlamaTokenOutputStream {
+            tokenizer: self,
+            tokens,
+            prev_index: 0,
+            current_index: index,
+        }
+    }
+}
+
+// Stateful decoder emitting only the text completed by each pushed token
+#[derive(Clone)]
+pub struct LlamaTokenOutputStream<'a> {
+    tokenizer: &'a LlamaTokenizer,
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl<'a> LlamaTokenOutputStream<'a> {
+    pub fn next_token(&mut self, token: u32) -> Result<Option<String>, CandleError> {
+        let prev_text = self.tokenizer.decode(&self.tokens[self.prev_index..self.current_index], true)?;
+        self.tokens.push(token);
+        let text = self.tokenizer.decode(&self.tokens[self.prev_index..], true)?;
+
+        // A trailing replacement character means a multi-byte character is still incomplete
+        if text.len() > prev_text.len() && !text.ends_with('\u{FFFD}') {
+            if let Some(chunk) = text.get(prev_text.len()..) {
+                self.prev_index = self.current_index;
+                self.current_index = self.tokens.len();
+                return Ok(Some(chunk.to_string()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Flush any text still held back, typically at end of sequence
+    pub fn decode_rest(&mut self) -> Result<Option<String>, CandleError> {
+        let prev_text = self.tokenizer.decode(&self.tokens[self.prev_index..self.current_index], true)?;
+        let text = self.tokenizer.decode(&self.tokens[self.prev_index..], true)?;
+
+        self.prev_index = self.tokens.len();
+        self.current_index = self.tokens.len();
+
+        match text.get(prev_text.len()..) {
+            Some(chunk) if !chunk.is_empty() => Ok(Some(chunk.to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+        self.prev_index = 0;
+        self.current_index = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    // Llama-2 style byte-fallback BPE, its decoder strips the leading space of the first token
+    const TOKENIZER_JSON: &str = r#"{
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [
+            { "id": 0, "content": "<unk>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true },
+            { "id": 1, "content": "<s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true },
+            { "id": 2, "content": "</s>", "single_word": false, "lstrip": false, "rstrip": false, "normalized": false, "special": true }
+        ],
+        "normalizer": null,
+        "pre_tokenizer": null,
+        "post_processor": null,
+        "decoder": {
+            "type": "Sequence",
+            "decoders": [
+                { "type": "Replace", "pattern": { "String": "▁" }, "content": " " },
+                { "type": "ByteFallback" },
+                { "type": "Fuse" },
+                { "type": "Strip", "content": " ", "start": 1, "stop": 0 }
+            ]
+        },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": "<unk>",
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": true,
+            "byte_fallback": true,
+            "vocab": { "<unk>": 0, "<s>": 1, "</s>": 2, "▁Hello": 3, "▁world": 4, "<0xC3>": 5, "<0xA9>": 6, "!": 7 },
+            "merges": []
+        }
+    }"#;
+
+    fn tokenizer() -> LlamaTokenizer {
+        let mut tokenizer = LlamaTokenizer::new(LlamaModelConfig::default());
+        tokenizer.tokenizer = Some(HfTokenizer::from_str(TOKENIZER_JSON).unwrap());
+        tokenizer
+    }
+
+    #[test]
+    fn test_output_stream_keeps_leading_spaces() {
+        let tokenizer = tokenizer();
+
+        // Decoded alone the first word loses its space, after the prompt it keeps it
+        let mut output = tokenizer.output_stream();
+        assert_eq!(output.next_token(4).unwrap().as_deref(), Some("world"));
+        let mut output = tokenizer.output_stream_after(&[1, 3]);
+        assert_eq!(output.next_token(4).unwrap().as_deref(), Some(" world"));
+        assert_eq!(output.next_token(7).unwrap().as_deref(), Some("!"));
+    }
+
+    #[test]
+    fn test_output_stream_holds_back_split_characters() {
+        let tokenizer = tokenizer();
+        let mut output = tokenizer.output_stream();
+        assert_eq!(output.next_token(3).unwrap().as_deref(), Some("Hello"));
+        assert_eq!(output.next_token(5).unwrap(), None);
+        assert_eq!(output.next_token(6).unwrap().as_deref(), Some("é"));
+    }
+
+    #[test]
+    fn test_output_stream_flushes_at_end_of_sequence() {
+        let tokenizer = tokenizer();
+        let mut output = tokenizer.output_stream();
+        assert_eq!(output.next_token(3).unwrap().as_deref(), Some("Hello"));
+        assert_eq!(output.next_token(5).unwrap(), None);
+        assert_eq!(output.decode_rest().unwrap().as_deref(), Some("\u{FFFD}"));
+        assert_eq!(output.decode_rest().unwrap(), None);
+    }
+
+    #[test]
+    fn test_eos_token_ids_accept_an_id_or_a_list() {
+        let tokenizer = tokenizer();
+        let hf_tokenizer = tokenizer.tokenizer.as_ref().unwrap();
+
+        let configs = [serde_json::json!({ "eos_token_id": 7 })];
+        assert_eq!(LlamaTokenizer::eos_token_ids_from_configs(&configs, hf_tokenizer), vec![7]);
+
+        // Ids from config.json and generation_config.json are merged
+        let configs = [
+            serde_json::json!({ "eos_token_id": [128001, 128009] }),
+            serde_json::json!({ "eos_token_id": 128001 }),
+        ];
+        assert_eq!(LlamaTokenizer::eos_token_ids_from_configs(&configs, hf_tokenizer), vec![128001, 128009]);
+    }
+
+    #[test]
+    fn test_eos_token_ids_fall_back_to_end_of_sequence_token() {
+        let tokenizer = tokenizer();
+        let hf_tokenizer = tokenizer.tokenizer.as_ref().unwrap();
+
+        let configs = [serde_json::json!({ "bos_token_id": 1 }), serde_json::json!({ "eos_token_id": null })];
+        assert_eq!(LlamaTokenizer::eos_token_ids_from_configs(&configs, hf_tokenizer), vec![2]);
+        assert_eq!(LlamaTokenizer::eos_token_ids_from_configs(&[], hf_tokenizer), vec![2]);
+    }
 }
\ No newline at end of file
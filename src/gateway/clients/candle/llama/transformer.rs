@@ -0,0 +1,376 @@
+// src/gateway/clients/candle/llama/transformer.rs
+
+/// Candle API Llama Transformer
+/// Llama forward pass following candle_transformers::models::llama, loading the same weights,
+/// extended with padding masks so left-padded batches can be decoded together.
+///
+/// Copied from candle-transformers 0.7 (models/llama.rs). Changes from upstream:
+/// - forward takes an optional padding mask, combined with the causal mask per row
+/// - LlamaCache gains select_rows, pad_left, append_rows and seq_len to reorder, align and
+///   merge per-row kv caches
+/// - forward_hidden returns the final hidden states for every position, logits applies lm_head
+///   to them separately
+
+// Core Crates
+use std::f32::consts::PI;
+
+// Candle Crates
+use candle_core::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{embedding, linear_no_bias as linear, rms_norm, Embedding, Linear, RmsNorm, VarBuilder};
+use candle_transformers::models::llama::{Config, Llama3RopeConfig, Llama3RopeType};
+use candle_transformers::utils::repeat_kv;
+
+#[derive(Debug, Clone)]
+pub struct LlamaCache {
+    pub use_kv_cache: bool,
+    kvs: Vec<Option<(Tensor, Tensor)>>,
+    cos: Tensor,
+    sin: Tensor,
+}
+
+fn calculate_default_inv_freq(config: &Config) -> Vec<f32> {
+    let head_dim = config.hidden_size / config.num_attention_heads;
+    (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / config.rope_theta.powf(i as f32 / head_dim as f32))
+        .collect()
+}
+
+impl LlamaCache {
+    pub fn new(use_kv_cache: bool, dtype: DType, config: &Config, device: &Device) -> Result<Self> {
+        // Precompute the rotary frequencies, applying Llama-3 rope scaling when configured
+        let theta = match &config.rope_scaling {
+            None | Some(Llama3RopeConfig { rope_type: Llama3RopeType::Default, .. }) => calculate_default_inv_freq(config),
+            Some(rope_scaling) => {
+                let original_max_position_embeddings = rope_scaling.original_max_position_embeddings as f32;
+                let low_freq_wavelen = original_max_position_embeddings / rope_scaling.low_freq_factor;
+                let high_freq_wavelen = original_max_position_embeddings / rope_scaling.high_freq_factor;
+
+                calculate_default_inv_freq(config)
+                    .into_iter()
+                    .map(|freq| {
+                        let wavelen = 2. * PI / freq;
+                        if wavelen < high_freq_wavelen {
+                            freq
+                        } else if wavelen > low_freq_wavelen {
+                            freq / rope_scaling.factor
+                        } else {
+                            let smooth = (original_max_position_embeddings / wavelen - rope_scaling.low_freq_factor)
+                                / (rope_scaling.high_freq_factor - rope_scaling.low_freq_factor);
+                            (1. - smooth) * freq / rope_scaling.factor + smooth * freq
+                        }
+                    })
+                    .collect::<Vec<f32>>()
+            }
+        };
+
+        let theta = Tensor::new(theta, device)?;
+        let idx_theta = Tensor::arange(0, config.max_position_embeddings as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((config.max_position_embeddings, 1))?
+            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+        let cos = idx_theta.cos()?.to_dtype(dtype)?;
+        let sin = idx_theta.sin()?.to_dtype(dtype)?;
+
+        Ok(LlamaCache {
+            use_kv_cache,
+            kvs: vec![None; config.num_hidden_layers],
+            cos,
+            sin,
+        })
+    }
+
+    // Number of cached key/value columns
+    pub fn seq_len(&self) -> usize {
+        self.kvs.iter()
+            .flatten()
+            .next()
+            .map(|(k, _)| k.dims()[2])
+            .unwrap_or(0)
+    }
+
+    // Keep only the given batch rows, used to retire finished sequences from a batch
+    pub fn select_rows(&mut self, rows: &Tensor) -> Result<()> {
+        for kv in self.kvs.iter_mut().flatten() {
+            *kv = (kv.0.index_select(rows, 0)?, kv.1.index_select(rows, 0)?);
+        }
+
+        Ok(())
+    }
+
+    // Prepend n zeroed columns, which callers must mask out
+    pub fn pad_left(&mut self, n: usize) -> Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+
+        for kv in self.kvs.iter_mut().flatten() {
+            let (b_sz, n_heads, _, head_dim) = kv.0.dims4()?;
+            let zeros = Tensor::zeros((b_sz, n_heads, n, head_dim), kv.0.dtype(), kv.0.device())?;
+            *kv = (Tensor::cat(&[&zeros, &kv.0], 2)?, Tensor::cat(&[&zeros, &kv.1], 2)?);
+        }
+
+        Ok(())
+    }
+
+    // Append the rows of another cache with the same number of columns
+    // The new columns are built aside so a failure leaves the cache unchanged
+    pub fn append_rows(&mut self, other: &LlamaCache) -> Result<()> {
+        let kvs = self.kvs.iter()
+            .zip(&other.kvs)
+            .map(|(kv, other_kv)| match (kv, other_kv) {
+                (Some((k, v)), Some((other_k, other_v))) => Ok(Some((Tensor::cat(&[k, other_k], 0)?, Tensor::cat(&[v, other_v], 0)?))),
+                (None, other_kv) => Ok(other_kv.clone()),
+                (kv, None) => Ok(kv.clone()),
+            })
+            .collect::<Result<Vec<Option<(Tensor, Tensor)>>>>()?;
+        self.kvs = kvs;
+
+        Ok(())
+    }
+}
+
+// Build an additive attention mask of shape (b, 1, seq_len, key_len)
+// Each padding mask row covers every cache column including the new tokens, the queries being the
+// last seq_len columns. Keys are visible when causal and not padding, every position can always
+// see itself so padded rows never end up with a fully masked softmax
+pub fn attention_mask(padding_mask: &[Vec<bool>], seq_len: usize, device: &Device) -> Result<Tensor> {
+    let key_len = padding_mask.first().map(|keys| keys.len()).unwrap_or(seq_len);
+    let mut mask = Vec::with_capacity(padding_mask.len() * seq_len * key_len);
+    for keys in padding_mask {
+        for query in 0..seq_len {
+            let query_pos = key_len - seq_len + query;
+            for key_pos in 0..key_len {
+                let visible = key_pos == query_pos || (key_pos < query_pos && keys[key_pos]);
+                mask.push(if visible { 0f32 } else { f32::NEG_INFINITY });
+            }
+        }
+    }
+
+    Tensor::from_vec(mask, (padding_mask.len(), 1, seq_len, key_len), device)
+}
+
+fn causal_mask(seq_len: usize, key_len: usize, device: &Device) -> Result<Tensor> {
+    let past_len = key_len - seq_len;
+    let mask = (0..seq_len)
+        .flat_map(|query| (0..key_len).map(move |key| if key > past_len + query { f32::NEG_INFINITY } else { 0f32 }))
+        .collect::<Vec<f32>>();
+
+    Tensor::from_vec(mask, (1, 1, seq_len, key_len), device)
+}
+
+#[cfg(feature = "flash-attn")]
+fn flash_attn(q: &Tensor, k: &Tensor, v: &Tensor, softmax_scale: f32, causal: bool) -> Result<Tensor> {
+    candle_flash_attn::flash_attn(q, k, v, softmax_scale, causal)
+}
+
+#[cfg(not(feature = "flash-attn"))]
+fn flash_attn(_: &Tensor, _: &Tensor, _: &Tensor, _: f32, _: bool) -> Result<Tensor> {
+    candle_core::bail!("compile with '--features flash-attn'")
+}
+
+struct CausalSelfAttention {
+    q_proj: Linear,
+    k_proj: Linear,
+    v_proj: Linear,
+    o_proj: Linear,
+    num_attention_heads: usize,
+    num_key_value_heads: usize,
+    head_dim: usize,
+    use_flash_attn: bool,
+}
+
+impl CausalSelfAttention {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize, cache: &LlamaCache) -> Result<Tensor> {
+        let (_b_sz, _, seq_len, _hidden_size) = x.dims4()?;
+        let cos = cache.cos.narrow(0, index_pos, seq_len)?;
+        let sin = cache.sin.narrow(0, index_pos, seq_len)?;
+
+        candle_nn::rotary_emb::rope(x, &cos, &sin)
+    }
+
+    fn forward(&self, x: &Tensor, index_pos: usize, block_idx: usize, cache: &mut LlamaCache, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        let q = self.q_proj.forward(x)?;
+        let k = self.k_proj.forward(x)?;
+        let v = self.v_proj.forward(x)?;
+
+        let q = q
+            .reshape((b_sz, seq_len, self.num_attention_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let k = k
+            .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let mut v = v
+            .reshape((b_sz, seq_len, self.num_key_value_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let q = self.apply_rotary_emb(&q, index_pos, cache)?;
+        let mut k = self.apply_rotary_emb(&k, index_pos, cache)?;
+
+        if cache.use_kv_cache {
+            if let Some((cache_k, cache_v)) = &cache.kvs[block_idx] {
+                k = Tensor::cat(&[cache_k, &k], 2)?.contiguous()?;
+                v = Tensor::cat(&[cache_v, &v], 2)?.contiguous()?;
+            }
+            cache.kvs[block_idx] = Some((k.clone(), v.clone()));
+        }
+
+        let n_rep = self.num_attention_heads / self.num_key_value_heads;
+        let k = repeat_kv(k, n_rep)?;
+        let v = repeat_kv(v, n_rep)?;
+
+        // Flash attention has no padding mask support, so it is only used for unpadded input
+        let y = if self.use_flash_attn && mask.is_none() {
+            // flash-attn expects (b_sz, seq_len, nheads, head_dim)
+            let q = q.transpose(1, 2)?;
+            let k = k.transpose(1, 2)?;
+            let v = v.transpose(1, 2)?;
+            let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
+            flash_attn(&q, &k, &v, softmax_scale, seq_len > 1)?.transpose(1, 2)?
+        } else {
+            let in_dtype = q.dtype();
+            let q = q.to_dtype(DType::F32)?;
+            let k = k.to_dtype(DType::F32)?;
+            let v = v.to_dtype(DType::F32)?;
+            let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+            let att = match mask {
+                Some(mask) => att.broadcast_add(mask)?,
+                None if seq_len > 1 => att.broadcast_add(&causal_mask(seq_len, k.dims()[2], x.device())?)?,
+                None => att,
+            };
+            let att = candle_nn::ops::softmax_last_dim(&att)?;
+            // Convert to contiguous as matmul doesn't support strided vs for now
+            att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
+        };
+
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, hidden_size])?;
+        self.o_proj.forward(&y)
+    }
+
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let size_in = config.hidden_size;
+        let size_q = (config.hidden_size / config.num_attention_heads) * config.num_attention_heads;
+        let size_kv = (config.hidden_size / config.num_attention_heads) * config.num_key_value_heads;
+
+        Ok(CausalSelfAttention {
+            q_proj: linear(size_in, size_q, vb.pp("q_proj"))?,
+            k_proj: linear(size_in, size_kv, vb.pp("k_proj"))?,
+            v_proj: linear(size_in, size_kv, vb.pp("v_proj"))?,
+            o_proj: linear(size_q, size_in, vb.pp("o_proj"))?,
+            num_attention_heads: config.num_attention_heads,
+            num_key_value_heads: config.num_key_value_heads,
+            head_dim: config.hidden_size / config.num_attention_heads,
+            use_flash_attn: config.use_flash_attn,
+        })
+    }
+}
+
+struct Mlp {
+    c_fc1: Linear,
+    c_fc2: Linear,
+    c_proj: Linear,
+}
+
+impl Mlp {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let x = (candle_nn::ops::silu(&self.c_fc1.forward(x)?)? * self.c_fc2.forward(x)?)?;
+        self.c_proj.forward(&x)
+    }
+
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let h_size = config.hidden_size;
+        let i_size = config.intermediate_size;
+
+        Ok(Mlp {
+            c_fc1: linear(h_size, i_size, vb.pp("gate_proj"))?,
+            c_fc2: linear(h_size, i_size, vb.pp("up_proj"))?,
+            c_proj: linear(i_size, h_size, vb.pp("down_proj"))?,
+        })
+    }
+}
+
+struct Block {
+    rms_1: RmsNorm,
+    attn: CausalSelfAttention,
+    rms_2: RmsNorm,
+    mlp: Mlp,
+}
+
+impl Block {
+    fn forward(&self, x: &Tensor, index_pos: usize, block_idx: usize, cache: &mut LlamaCache, mask: Option<&Tensor>) -> Result<Tensor> {
+        let residual = x;
+        let x = self.rms_1.forward(x)?;
+        let x = (self.attn.forward(&x, index_pos, block_idx, cache, mask)? + residual)?;
+        let residual = &x;
+        let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
+
+        Ok(x)
+    }
+
+    fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        Ok(Block {
+            rms_1: rms_norm(config.hidden_size, config.rms_norm_eps, vb.pp("input_layernorm"))?,
+            attn: CausalSelfAttention::load(vb.pp("self_attn"), config)?,
+            rms_2: rms_norm(config.hidden_size, config.rms_norm_eps, vb.pp("post_attention_layernorm"))?,
+            mlp: Mlp::load(vb.pp("mlp"), config)?,
+        })
+    }
+}
+
+pub struct LlamaTransformer {
+    wte: Embedding,
+    blocks: Vec<Block>,
+    ln_f: RmsNorm,
+    lm_head: Linear,
+}
+
+impl LlamaTransformer {
+    // Final normalized hidden states for every position, shape (b, seq_len, hidden_size)
+    pub fn forward_hidden(&self, x: &Tensor, index_pos: usize, cache: &mut LlamaCache, mask: Option<&Tensor>) -> Result<Tensor> {
+        let mut x = self.wte.forward(x)?;
+        for (block_idx, block) in self.blocks.iter().enumerate() {
+            x = block.forward(&x, index_pos, block_idx, cache, mask)?;
+        }
+
+        self.ln_f.forward(&x)
+    }
+
+    // Logits for the last position of every row, shape (b, vocab_size)
+    pub fn forward(&self, x: &Tensor, index_pos: usize, cache: &mut LlamaCache, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let x = self.forward_hidden(x, index_pos, cache, mask)?;
+        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
+
+        self.logits(&x)
+    }
+
+    // Project hidden states onto the vocabulary, any leading dimensions are kept
+    pub fn logits(&self, hidden: &Tensor) -> Result<Tensor> {
+        let logits = self.lm_head.forward(hidden)?;
+
+        logits.to_dtype(DType::F32)
+    }
+
+    pub fn load(vb: VarBuilder, config: &Config) -> Result<Self> {
+        let wte = embedding(config.vocab_size, config.hidden_size, vb.pp("model.embed_tokens"))?;
+        let lm_head = if config.tie_word_embeddings {
+            Linear::new(wte.embeddings().clone(), None)
+        } else {
+            linear(config.hidden_size, config.vocab_size, vb.pp("lm_head"))?
+        };
+        let ln_f = rms_norm(config.hidden_size, config.rms_norm_eps, vb.pp("model.norm"))?;
+        let blocks = (0..config.num_hidden_layers)
+            .map(|i| Block::load(vb.pp(format!("model.layers.{i}")), config))
+            .collect::<Result<Vec<Block>>>()?;
+
+        Ok(LlamaTransformer {
+            wte,
+            blocks,
+            ln_f,
+            lm_head,
+        })
+    }
+}
\ No newline at end of file
@@ -0,0 +1,334 @@
+// src/gateway/clients/candle/logits.rs
+
+/// Candle API Logits Pipeline
+/// Composable stages that transform raw logits before a sampler picks the next token.
+/// New strategies are added as stages without touching the generation loop.
+
+// Core Crates
+use std::collections::HashMap;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+// Candle Crates
+use candle_core::{DType, Tensor};
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+
+pub trait LogitsStage: Send + Sync {
+    // Transform the logits in place, given the tokens seen so far
+    fn apply(&self, logits: &mut [f32], context: &[u32]);
+}
+
+pub enum LogitsSampler {
+    Greedy,
+    Multinomial(StdRng),
+}
+
+pub struct LogitsPipeline {
+    stages: Vec<Box<dyn LogitsStage>>,
+    sampler: LogitsSampler,
+}
+
+impl LogitsPipeline {
+    pub fn greedy() -> Self {
+        LogitsPipeline {
+            stages: Vec::new(),
+            sampler: LogitsSampler::Greedy,
+        }
+    }
+
+    pub fn multinomial(seed: u64) -> Self {
+        LogitsPipeline {
+            stages: Vec::new(),
+            sampler: LogitsSampler::Multinomial(StdRng::seed_from_u64(seed)),
+        }
+    }
+
+    pub fn with_stage(mut self, stage: impl LogitsStage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    // Run every stage over the logits for a single position
+    pub fn process(&self, logits: &Tensor, context: &[u32]) -> Result<Vec<f32>, CandleError> {
+        let mut logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        for stage in &self.stages {
+            stage.apply(&mut logits, context);
+        }
+
+        Ok(logits)
+    }
+
+    // Pick a token from logits that have already been processed
+    pub fn sample_processed(&mut self, logits: &[f32]) -> Result<u32, CandleError> {
+        match &mut self.sampler {
+            LogitsSampler::Greedy => Ok(argmax(logits)),
+            LogitsSampler::Multinomial(rng) => {
+                let probs = softmax(logits);
+                let distribution = WeightedIndex::new(&probs)
+                    .map_err(|err| CandleError::InvalidParameterError(format!("Cannot sample from logits: {}", err)))?;
+
+                Ok(distribution.sample(rng) as u32)
+            }
+        }
+    }
+
+    pub fn sample(&mut self, logits: &Tensor, context: &[u32]) -> Result<u32, CandleError> {
+        let logits = self.process(logits, context)?;
+        self.sample_processed(&logits)
+    }
+}
+
+/// Logits Stages
+
+// Penalize tokens seen in the last `last_n` context tokens, 1.0 means no penalty
+pub struct RepeatPenalty {
+    pub penalty: f32,
+    pub last_n: usize,
+}
+
+impl LogitsStage for RepeatPenalty {
+    fn apply(&self, logits: &mut [f32], context: &[u32]) {
+        let start_at = context.len().saturating_sub(self.last_n);
+        let mut seen = context[start_at..].to_vec();
+        seen.sort_unstable();
+        seen.dedup();
+
+        for token in seen {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                if *logit >= 0.0 {
+                    *logit /= self.penalty;
+                } else {
+                    *logit *= self.penalty;
+                }
+            }
+        }
+    }
+}
+
+// OpenAI-style penalties, scaled by how often a token was generated and whether it was generated at all
+// The first prompt_tokens of the context are the prompt and are not counted
+pub struct FrequencyPresencePenalty {
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    pub prompt_tokens: usize,
+}
+
+impl LogitsStage for FrequencyPresencePenalty {
+    fn apply(&self, logits: &mut [f32], context: &[u32]) {
+        let mut counts = HashMap::new();
+        for &token in context.get(self.prompt_tokens..).unwrap_or_default() {
+            *counts.entry(token).or_insert(0usize) += 1;
+        }
+
+        for (token, count) in counts {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= count as f32 * self.frequency_penalty + self.presence_penalty;
+            }
+        }
+    }
+}
+
+// Add a fixed bias to specific token ids, -100 effectively bans a token
+pub struct LogitBias(pub HashMap<u32, f32>);
+
+impl LogitsStage for LogitBias {
+    fn apply(&self, logits: &mut [f32], _context: &[u32]) {
+        for (&token, &bias) in &self.0 {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit += bias;
+            }
+        }
+    }
+}
+
+pub struct Temperature(pub f32);
+
+impl LogitsStage for Temperature {
+    fn apply(&self, logits: &mut [f32], _context: &[u32]) {
+        for logit in logits.iter_mut() {
+            *logit /= self.0;
+        }
+    }
+}
+
+// Keep only the k most likely tokens
+pub struct TopK(pub usize);
+
+impl LogitsStage for TopK {
+    fn apply(&self, logits: &mut [f32], _context: &[u32]) {
+        if self.0 >= logits.len() {
+            return;
+        }
+
+        let mut sorted = logits.to_vec();
+        sorted.sort_unstable_by(|a, b| b.total_cmp(a));
+        let threshold = sorted[self.0 - 1];
+
+        // Ties at the threshold are kept so the result does not depend on sort order
+        for logit in logits.iter_mut() {
+            if *logit < threshold {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+// Keep the smallest set of tokens whose cumulative probability reaches p
+pub struct TopP(pub f32);
+
+impl LogitsStage for TopP {
+    fn apply(&self, logits: &mut [f32], _context: &[u32]) {
+        let probs = softmax(logits);
+        let mut indices = (0..probs.len()).collect::<Vec<usize>>();
+        indices.sort_unstable_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+
+        let mut cumulative = 0.0;
+        let mut keep = indices.len();
+        for (rank, &index) in indices.iter().enumerate() {
+            cumulative += probs[index];
+            if cumulative >= self.0 {
+                keep = rank + 1;
+                break;
+            }
+        }
+
+        for &index in &indices[keep..] {
+            logits[index] = f32::NEG_INFINITY;
+        }
+    }
+}
+
+// Drop tokens whose probability is below min_p times that of the most likely token
+pub struct MinP(pub f32);
+
+impl LogitsStage for MinP {
+    fn apply(&self, logits: &mut [f32], _context: &[u32]) {
+        let probs = softmax(logits);
+        let threshold = probs.iter().cloned().fold(0.0, f32::max) * self.0;
+
+        for (logit, prob) in logits.iter_mut().zip(probs) {
+            if prob < threshold {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+// Locally typical sampling, keeps tokens whose surprisal is closest to the entropy
+pub struct TypicalP(pub f32);
+
+impl LogitsStage for TypicalP {
+    fn apply(&self, logits: &mut [f32], _context: &[u32]) {
+        let probs = softmax(logits);
+        let entropy = probs.iter()
+            .filter(|&&prob| prob > 0.0)
+            .map(|&prob| -prob * prob.ln())
+            .sum::<f32>();
+
+        let mut indices = (0..probs.len()).filter(|&index| probs[index] > 0.0).collect::<Vec<usize>>();
+        let deviation = |index: usize| (-probs[index].ln() - entropy).abs();
+        indices.sort_unstable_by(|&a, &b| deviation(a).total_cmp(&deviation(b)));
+
+        let mut keep = vec![false; probs.len()];
+        let mut cumulative = 0.0;
+        for index in indices {
+            keep[index] = true;
+            cumulative += probs[index];
+            if cumulative >= self.0 {
+                break;
+            }
+        }
+
+        for (logit, keep) in logits.iter_mut().zip(keep) {
+            if !keep {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+/// Logits Utilities
+
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exps = logits.iter().map(|&logit| (logit - max).exp()).collect::<Vec<f32>>();
+    let sum = exps.iter().sum::<f32>();
+
+    exps.into_iter().map(|exp| exp / sum).collect()
+}
+
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|&logit| (logit - max).exp()).sum::<f32>().ln();
+
+    logits.iter().map(|&logit| logit - max - log_sum).collect()
+}
+
+// The n highest values with their token ids, most likely first
+pub fn top_n(values: &[f32], n: usize) -> Vec<(u32, f32)> {
+    let mut indices = (0..values.len()).collect::<Vec<usize>>();
+    let n = n.min(indices.len());
+    if n == 0 {
+        return Vec::new();
+    }
+
+    indices.select_nth_unstable_by(n - 1, |&a, &b| values[b].total_cmp(&values[a]));
+    indices.truncate(n);
+    indices.sort_unstable_by(|&a, &b| values[b].total_cmp(&values[a]));
+
+    indices.into_iter().map(|index| (index as u32, values[index])).collect()
+}
+
+pub fn argmax(logits: &[f32]) -> u32 {
+    logits.iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_k_keeps_k_largest() {
+        let mut logits = vec![1.0, 4.0, 3.0, 2.0];
+        TopK(2).apply(&mut logits, &[]);
+        assert_eq!(logits, vec![f32::NEG_INFINITY, 4.0, 3.0, f32::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn test_top_p_keeps_most_likely_token() {
+        let mut logits = vec![0.0, 10.0, 0.0];
+        TopP(0.5).apply(&mut logits, &[]);
+        assert_eq!(argmax(&logits), 1);
+        assert!(logits[0].is_infinite() && logits[2].is_infinite());
+    }
+
+    #[test]
+    fn test_min_p_drops_unlikely_tokens() {
+        let mut logits = vec![5.0, 4.9, -5.0];
+        MinP(0.1).apply(&mut logits, &[]);
+        assert!(logits[0].is_finite() && logits[1].is_finite());
+        assert!(logits[2].is_infinite());
+    }
+
+    #[test]
+    fn test_frequency_presence_penalty_skips_prompt() {
+        let mut logits = vec![1.0, 1.0, 1.0];
+        let penalty = FrequencyPresencePenalty { frequency_penalty: 0.5, presence_penalty: 0.25, prompt_tokens: 2 };
+        penalty.apply(&mut logits, &[0, 0, 1, 1]);
+        assert_eq!(logits, vec![1.0, -0.25, 1.0]);
+    }
+
+    #[test]
+    fn test_logit_bias_adds_to_tokens() {
+        let mut logits = vec![1.0, 1.0];
+        LogitBias(HashMap::from([(1, -100.0)])).apply(&mut logits, &[]);
+        assert_eq!(logits, vec![1.0, -99.0]);
+    }
+}
\ No newline at end of file
@@ -1,15 +1,20 @@
 // src/gateway/clients/candle/mod.rs
 
 /// Candle API Mods
+pub mod bert;
 pub mod candle_error;
 pub mod llama;
+pub mod logits;
+pub mod model_source;
+pub mod pooling;
 
 // Core Crates
 use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 
 // Candle Crates
-use candle_core::DType;
+use candle_core::{Device, DType};
+use candle_core::utils::{cuda_is_available, metal_is_available};
 use crate::gateway::clients::candle::candle_error::CandleError;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -33,9 +38,48 @@ pub struct GenerateTextResponse {
 /// Candle API Utilities
 /// Utilities relating to Candle API functions
 
+// Select the device to run on
+// Falls back to the CPU when no accelerator is present
+pub fn select_device(cpu: bool) -> Result<Device, CandleError> {
+    if cpu {
+        return Ok(Device::Cpu);
+    }
+
+    if cuda_is_available() {
+        Ok(Device::new_cuda(0)?)
+    } else if metal_is_available() {
+        Ok(Device::new_metal(0)?)
+    } else {
+        println!("No GPU available, falling back to CPU");
+        Ok(Device::Cpu)
+    }
+}
+
+// Select the dtype to load weights and caches with
+// Defaults to F32 on CPU and F16 on accelerators when none is configured
+pub fn select_dtype(dtype: Option<SerializableDType>, device: &Device, use_flash_attn: bool) -> Result<DType, CandleError> {
+    let dtype = match dtype {
+        Some(dtype) => DType::from(dtype),
+        None if device.is_cpu() => DType::F32,
+        None => DType::F16,
+    };
+
+    // Metal kernels have no BF16 matmul support
+    if device.is_metal() && dtype == DType::BF16 {
+        return Err(CandleError::UnsupportedDTypeError(dtype));
+    }
+
+    // Flash attention only runs on CUDA with half precision
+    if use_flash_attn && (!device.is_cuda() || dtype == DType::F32) {
+        return Err(CandleError::UnsupportedDTypeError(dtype));
+    }
+
+    Ok(dtype)
+}
+
 // Serialize DType
 // Candle's default DType is not serializable
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum SerializableDType {
     F16,
     BF16,
@@ -0,0 +1,98 @@
+// src/gateway/clients/candle/model_source.rs
+
+/// Candle API Model Source
+/// Resolves model files from the Hugging Face hub, a local directory, or a pre-populated HF cache.
+
+// Core Crates
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+// Candle Crates
+use hf_hub::{Cache, CacheRepo, Repo, RepoType};
+use hf_hub::api::tokio::{ApiBuilder, ApiError, ApiRepo};
+
+// Networking Crates
+use reqwest::StatusCode;
+use crate::gateway::clients::candle::candle_error::CandleError;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum ModelSource {
+    // Download files from the hub, reusing anything already in the HF cache
+    #[default]
+    Hub,
+
+    // Read files from a directory holding tokenizer.json, config.json and the safetensors
+    LocalDir(PathBuf),
+
+    // Read files from an HF cache without network access, defaulting to ~/.cache/huggingface/hub
+    HfCache(Option<PathBuf>),
+}
+
+pub enum ModelRepo {
+    Hub(ApiRepo),
+    LocalDir(PathBuf),
+    HfCache(CacheRepo),
+}
+
+impl ModelRepo {
+    pub fn new(source: &ModelSource, model_id: &str, revision: Option<&str>) -> Result<Self, CandleError> {
+        let repo = match revision {
+            Some(revision) => Repo::with_revision(model_id.to_string(), RepoType::Model, revision.to_string()),
+            None => Repo::new(model_id.to_string(), RepoType::Model),
+        };
+
+        match source {
+            ModelSource::Hub => {
+                let api = ApiBuilder::new()
+                    .build()
+                    .map_err(|_| CandleError::InitializationError("Failed to create API".into()))?;
+
+                Ok(ModelRepo::Hub(api.repo(repo)))
+            }
+            ModelSource::LocalDir(dir) => Ok(ModelRepo::LocalDir(dir.clone())),
+            ModelSource::HfCache(cache_dir) => {
+                let cache = match cache_dir {
+                    Some(cache_dir) => Cache::new(cache_dir.clone()),
+                    None => Cache::default(),
+                };
+
+                Ok(ModelRepo::HfCache(cache.repo(repo)))
+            }
+        }
+    }
+
+    // Resolve a repository file to a local path, downloading it only when using the hub
+    pub async fn get(&self, filename: &str) -> Result<PathBuf, CandleError> {
+        match self {
+            ModelRepo::Hub(repo) => repo.get(filename).await
+                .map_err(|_| CandleError::DownloadError(format!("Failed to get {}", filename))),
+            ModelRepo::LocalDir(dir) => {
+                let path = dir.join(filename);
+                if path.is_file() {
+                    Ok(path)
+                } else {
+                    Err(CandleError::LoadModelError(format!("{} not found in {}", filename, dir.display())))
+                }
+            }
+            ModelRepo::HfCache(repo) => repo.get(filename)
+                .ok_or_else(|| CandleError::LoadModelError(format!("{} not found in HF cache", filename))),
+        }
+    }
+
+    // Resolve an optional repository file, None when the repository has no such file
+    // Any other failure, such as a network error, is still returned as an error
+    pub async fn find(&self, filename: &str) -> Result<Option<PathBuf>, CandleError> {
+        match self {
+            ModelRepo::Hub(repo) => match repo.get(filename).await {
+                Ok(path) => Ok(Some(path)),
+                Err(ApiError::RequestError(err)) if err.status() == Some(StatusCode::NOT_FOUND) => Ok(None),
+                Err(_) => Err(CandleError::DownloadError(format!("Failed to get {}", filename))),
+            },
+            ModelRepo::LocalDir(dir) => {
+                let path = dir.join(filename);
+                Ok(path.is_file().then_some(path))
+            }
+            ModelRepo::HfCache(repo) => Ok(repo.get(filename)),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,93 @@
+// src/gateway/clients/candle/pooling.rs
+
+/// Candle API Pooling
+/// Turns per-token hidden states into a single embedding per input, shared by the embedding clients.
+
+// Core Crates
+use serde::{Deserialize, Serialize};
+
+// Candle Crates
+use candle_core::{DType, Tensor};
+
+// Networking Crates
+use crate::gateway::clients::candle::candle_error::CandleError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pooling {
+    // Average over the non-padding tokens
+    #[default]
+    Mean,
+    // Hidden state of the last non-padding token, suited to causal models
+    LastToken,
+    // Hidden state of the first token, the [CLS] token of BERT-style encoders
+    Cls,
+}
+
+// Pool hidden states of shape (b, seq_len, hidden_size) into (b, hidden_size)
+// The mask has shape (b, seq_len), 1 for real tokens and 0 for padding
+pub fn pool(hidden: &Tensor, mask: &Tensor, pooling: Pooling) -> Result<Tensor, CandleError> {
+    let hidden = hidden.to_dtype(DType::F32)?;
+    let mask = mask.to_dtype(DType::F32)?;
+
+    match pooling {
+        Pooling::Mean => {
+            let summed = hidden.broadcast_mul(&mask.unsqueeze(2)?)?.sum(1)?;
+            let counts = mask.sum_keepdim(1)?.clamp(1f32, f32::MAX)?;
+
+            Ok(summed.broadcast_div(&counts)?)
+        }
+        Pooling::LastToken => {
+            let mut rows = Vec::new();
+            for (row, keys) in mask.to_vec2::<f32>()?.iter().enumerate() {
+                let last = keys.iter().rposition(|&key| key > 0.0).unwrap_or(0);
+                rows.push(hidden.get(row)?.get(last)?);
+            }
+
+            Ok(Tensor::stack(&rows, 0)?)
+        }
+        Pooling::Cls => Ok(hidden.narrow(1, 0, 1)?.squeeze(1)?),
+    }
+}
+
+// Scale every embedding to unit length
+pub fn normalize_l2(embeddings: &Tensor) -> Result<Tensor, CandleError> {
+    let norms = embeddings.sqr()?.sum_keepdim(1)?.sqrt()?.clamp(1e-12f32, f32::MAX)?;
+
+    Ok(embeddings.broadcast_div(&norms)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    // Two rows of three positions with hidden size 2, the second row padded after two tokens
+    fn hidden() -> (Tensor, Tensor) {
+        let hidden = Tensor::new(&[[[1f32, 2.], [3., 4.], [5., 6.]], [[1., 1.], [3., 3.], [100., 100.]]], &Device::Cpu).unwrap();
+        let mask = Tensor::new(&[[1f32, 1., 1.], [1., 1., 0.]], &Device::Cpu).unwrap();
+
+        (hidden, mask)
+    }
+
+    #[test]
+    fn test_mean_pooling_skips_padding() {
+        let (hidden, mask) = hidden();
+        let pooled = pool(&hidden, &mask, Pooling::Mean).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(pooled, vec![vec![3., 4.], vec![2., 2.]]);
+    }
+
+    #[test]
+    fn test_last_token_pooling_skips_right_padding() {
+        let (hidden, mask) = hidden();
+        let pooled = pool(&hidden, &mask, Pooling::LastToken).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(pooled, vec![vec![5., 6.], vec![3., 3.]]);
+    }
+
+    #[test]
+    fn test_normalize_l2_gives_unit_length() {
+        let embeddings = Tensor::new(&[[3f32, 4.], [0., 0.]], &Device::Cpu).unwrap();
+        let normalized = normalize_l2(&embeddings).unwrap().to_vec2::<f32>().unwrap();
+        assert_eq!(normalized, vec![vec![0.6, 0.8], vec![0., 0.]]);
+    }
+}
\ No newline at end of file
@@ -21,7 +21,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Create a sample request
     let request = LlamaGenerateTextRequest {
         prompt: "Please provide a brief introduction to the Rust programming language.".to_string(),
-        config: Default::default(),
+        sampling: Default::default(),
+        stop: Vec::new(),
+        echo: false,
+        logprobs: false,
+        top_logprobs: None,
+        beam_search: None,
+        n: None,
+        truncation: None,
     };
 
     // Call the generate_text method on the Llama model instance
@@ -11,6 +11,8 @@ pub struct TelemetryData {
     pub request_summary: String,
     pub response_summary: String,
     pub error_info: Option<String>,
+    // Requests waiting for a slot in the Candle scheduler's running batch
+    pub queue_depth: Option<usize>,
 }
 
 pub async fn send_telemetry(data: &TelemetryData) -> Result<(), NetworkError> {